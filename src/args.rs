@@ -3,11 +3,11 @@ use crate::storage::Storage;
 use std::{net::SocketAddr, path::PathBuf};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use serde::{Deserialize, Serialize};
 
 use melstf::GenesisConfig;
-use melstructs::Address;
+use melstructs::{Address, BlockHeight, Checkpoint};
 use tap::Tap;
 use tmelcrypt::Ed25519SK;
 
@@ -54,6 +54,60 @@ pub struct MainArgs {
     /// Create an in-memory coin index. **RPC endpoints that rely on this will be disabled if this is not set!**
     #[arg(long)]
     pub index_coins: bool,
+
+    /// Trusted checkpoint, given as `height:header_hash`. A node with an empty database fetches the state at this checkpoint from its peers and syncs onwards from there, instead of replaying every block since genesis.
+    #[arg(long)]
+    pub trust_checkpoint: Option<Checkpoint>,
+
+    /// If given, only keep this many of the newest blocks in full. Older blocks are reduced to their headers and transaction hashes; the state SMTs they alone reference stay on disk until reclaimed with melnode-compact. Cannot be combined with `--index-coins`, since the coin indexer needs every block since genesis.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "index_coins")]
+    prune_keep: Option<u64>,
+
+    /// If given, also serve a read-only HTTP/JSON API at this address, with endpoints like `/summary` and `/block/{height}`.
+    #[arg(long)]
+    pub http_listen: Option<SocketAddr>,
+
+    /// Listen address for the Prometheus metrics webserver.
+    #[cfg(feature = "metrics")]
+    #[arg(long, default_value = "0.0.0.0:8080")]
+    metrics_listen: SocketAddr,
+
+    /// Instead of running the node, do something else with its database.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// One-off commands operating on the node's database, run instead of the node itself.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Export or import state snapshots, for bootstrapping new nodes without replaying the whole history.
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+}
+
+/// Snapshot subcommands.
+#[derive(Debug, Subcommand)]
+pub enum SnapshotCommand {
+    /// Write a self-contained snapshot of the state at a given height.
+    Export {
+        /// Height of the state to export
+        #[arg(long)]
+        height: BlockHeight,
+
+        /// Path of the snapshot file to write
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Seed an empty database with a snapshot, after verifying it against a trusted checkpoint.
+    Import {
+        /// Path of the snapshot file to read
+        #[arg(long)]
+        input: PathBuf,
+
+        /// Trusted checkpoint the snapshot must be of, given as `height:header_hash`
+        #[arg(long)]
+        checkpoint: Checkpoint,
+    },
 }
 
 /// Staker configuration, YAML-deserializable.
@@ -111,7 +165,7 @@ impl MainArgs {
             .tap_mut(|path| path.push("smt.db"));
 
         std::fs::create_dir_all(&database_base_path)?;
-        let storage = Storage::open(database_base_path, genesis)
+        let storage = Storage::open(database_base_path, genesis, self.prune_keep)
             .await
             .context("cannot make storage")?;
 
@@ -147,6 +201,12 @@ impl MainArgs {
         self.listen
     }
 
+    /// Prometheus metrics listening address
+    #[cfg(feature = "metrics")]
+    pub fn metrics_listen_addr(&self) -> SocketAddr {
+        self.metrics_listen
+    }
+
     /// Legacy listening address
     pub fn legacy_listen_addr(&self) -> Option<SocketAddr> {
         self.legacy_listen
@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use melnode::storage::Storage;
+use melstf::GenesisConfig;
+use tap::Tap;
+
+/// Offline compaction of a melnode database. Rewrites the SMT forest with only the nodes reachable from the states in the block history, dropping everything else. Never run this while melnode is using the same database!
+#[derive(Debug, Parser)]
+struct Args {
+    /// Database path, as given to melnode
+    #[arg(long)]
+    database: Option<PathBuf>,
+
+    /// If given, uses this YAML file as the network genesis, as given to melnode
+    #[arg(long)]
+    override_genesis: Option<PathBuf>,
+
+    /// Compact the testnet database rather than the mainnet one
+    #[arg(long)]
+    testnet: bool,
+
+    /// Only compact if pruning (see melnode's --prune-keep) has left unreferenced SMT nodes behind, so that this can cheaply run before every start of a pruned node
+    #[arg(long)]
+    if_pruned: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    if std::env::var("RUST_LOG").is_err() {
+        std::env::set_var("RUST_LOG", "melnode=debug,warn");
+    }
+    env_logger::Builder::from_env("RUST_LOG").init();
+    let args = Args::parse();
+
+    let genesis = if let Some(path) = &args.override_genesis {
+        let genesis_yaml = std::fs::read(path).context("cannot read genesis config")?;
+        serde_yaml::from_slice(&genesis_yaml).context("error while parsing genesis config")?
+    } else if args.testnet {
+        GenesisConfig::std_testnet()
+    } else {
+        GenesisConfig::std_mainnet()
+    };
+    let database = args.database.unwrap_or_else(|| {
+        dirs::home_dir()
+            .expect("no home dir?!")
+            .tap_mut(|p| p.push(".melnode/"))
+    });
+
+    if args.if_pruned && !Storage::has_pruned_garbage(database.clone(), &genesis)? {
+        eprintln!(
+            "nothing was pruned since the last compaction, leaving {:?} alone",
+            database
+        );
+        return Ok(());
+    }
+    eprintln!("compacting the SMT forest in {:?}...", database);
+    let stats = Storage::compact(database, &genesis)?;
+    eprintln!(
+        "kept {} reachable nodes; {} -> {} bytes ({} bytes reclaimed)",
+        stats.nodes_kept,
+        stats.bytes_before,
+        stats.bytes_after,
+        stats.bytes_reclaimed()
+    );
+    Ok(())
+}
@@ -17,7 +17,7 @@ struct Args {
 fn main() -> anyhow::Result<()> {
     smolscale::block_on(async move {
         let args = Args::parse();
-        let storage = Storage::open(args.new, GenesisConfig::std_mainnet()).await?;
+        let storage = Storage::open(args.new, GenesisConfig::std_mainnet(), None).await?;
         let directory = std::fs::read_dir(args.old)?;
         let mut paths = vec![];
         for file in directory {
@@ -0,0 +1,95 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_util::Future;
+use smol::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use smol_timeout::TimeoutExt;
+
+/// Most bytes of request line and headers read from a client, which is plenty for a `GET`.
+const MAX_REQUEST_HEAD: u64 = 8192;
+
+/// How long a client has to send its request line and headers before it's dropped.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A response to a single HTTP request.
+pub struct HttpResponse {
+    pub status: &'static str,
+    /// Headers besides `Content-Length` and `Connection`, which are always sent.
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Starts a minimal HTTP/1.1 server on the given address, for the metrics endpoint and the HTTP gateway. Every connection gets a single response from `handle`, which is given the request line, and is then closed. Connections for which `handle` fails are closed without a response.
+pub async fn start_http_server<F, Fut>(
+    listen_addr: SocketAddr,
+    handle: F,
+) -> anyhow::Result<smol::Task<()>>
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<HttpResponse>> + Send + 'static,
+{
+    let listener = TcpListener::bind(listen_addr).await?;
+    let handle = Arc::new(handle);
+    Ok(smolscale::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((conn, _)) => {
+                    let handle = handle.clone();
+                    smolscale::spawn(async move {
+                        if let Err(err) = handle_conn(conn, handle.as_ref()).await {
+                            log::trace!("HTTP connection to {listen_addr} failed: {:?}", err)
+                        }
+                    })
+                    .detach();
+                }
+                Err(err) => log::warn!("cannot accept HTTP connection at {listen_addr}: {:?}", err),
+            }
+        }
+    }))
+}
+
+/// Responds to a single HTTP request.
+async fn handle_conn<F, Fut>(mut conn: TcpStream, handle: &F) -> anyhow::Result<()>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<HttpResponse>>,
+{
+    let request_line = read_request_head(conn.clone())
+        .timeout(REQUEST_HEAD_TIMEOUT)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("timed out reading the request"))??;
+    let response = handle(request_line).await?;
+
+    let mut head = format!("HTTP/1.1 {}\r\n", response.status);
+    for (name, value) in response.headers {
+        head += &format!("{name}: {value}\r\n");
+    }
+    head += &format!(
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    );
+    conn.write_all(head.as_bytes()).await?;
+    conn.write_all(&response.body).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// Reads the request line and headers of an HTTP request, returning just the request line. Clients sending more than [MAX_REQUEST_HEAD] bytes before the end of the headers are cut off.
+async fn read_request_head(conn: TcpStream) -> anyhow::Result<String> {
+    let mut reader = BufReader::new(conn).take(MAX_REQUEST_HEAD);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // skip the headers, we don't care about them
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    if reader.limit() == 0 {
+        anyhow::bail!("request too long");
+    }
+    Ok(request_line)
+}
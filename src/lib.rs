@@ -3,5 +3,8 @@ pub mod args;
 pub mod node;
 
 pub mod autoretry;
+pub mod http_server;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod staker;
 pub mod storage;
@@ -1,4 +1,9 @@
-use melnode::{args::MainArgs, node::Node, staker::Staker, storage::Storage};
+use melnode::{
+    args::{Command, MainArgs, SnapshotCommand},
+    node::Node,
+    staker::Staker,
+    storage::Storage,
+};
 
 use anyhow::Context;
 
@@ -36,10 +41,17 @@ pub async fn main_async(opt: MainArgs) -> anyhow::Result<()> {
     let genesis = opt.genesis_config().await?;
     let netid = genesis.network;
     let storage: Storage = opt.storage().await?;
+    if let Some(command) = &opt.command {
+        return run_command(command, storage).await;
+    }
     let bootstrap = opt.bootstrap().await?;
 
     log::info!("bootstrapping with {:?}", bootstrap);
 
+    #[cfg(feature = "metrics")]
+    let _metrics_task =
+        melnode::metrics::start_server(netid, opt.metrics_listen_addr(), &storage).await?;
+
     let swarm: Swarm<HttpBackhaul, NodeRpcClient> =
         Swarm::new(HttpBackhaul::new(), NodeRpcClient, "melnode");
 
@@ -55,6 +67,8 @@ pub async fn main_async(opt: MainArgs) -> anyhow::Result<()> {
         storage.clone(),
         opt.index_coins,
         swarm.clone(),
+        opt.trust_checkpoint.clone(),
+        opt.http_listen,
     )
     .await?;
 
@@ -142,3 +156,22 @@ pub async fn main_async(opt: MainArgs) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Runs a one-off command on the node's storage, instead of the node itself.
+async fn run_command(command: &Command, storage: Storage) -> anyhow::Result<()> {
+    match command {
+        Command::Snapshot(SnapshotCommand::Export { height, output }) => {
+            log::info!("exporting snapshot of height {height} to {:?}", output);
+            let nodes = storage.export_snapshot(*height, output.clone()).await?;
+            log::info!("exported snapshot with {nodes} SMT nodes");
+        }
+        Command::Snapshot(SnapshotCommand::Import { input, checkpoint }) => {
+            log::info!("importing snapshot from {:?}", input);
+            storage
+                .import_snapshot(input.clone(), checkpoint.clone())
+                .await?;
+        }
+    }
+    storage.forest().storage().flush();
+    Ok(())
+}
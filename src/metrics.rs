@@ -0,0 +1,138 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use async_trait::async_trait;
+use melstructs::NetID;
+use nanorpc::{RpcService, ServerError};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use crate::{
+    http_server::{start_http_server, HttpResponse},
+    storage::Storage,
+};
+
+/// Highest block height in storage.
+pub static HIGHEST_BLOCK: Lazy<IntGauge> =
+    Lazy::new(|| IntGauge::new("highest_block", "Highest Block").unwrap());
+
+/// How many blocks we were behind the peer we last synced with.
+pub static BLKSYNC_LAG: Lazy<IntGauge> = Lazy::new(|| {
+    IntGauge::new(
+        "blksync_lag_blocks",
+        "Blocks Behind the Last Synced Peer (In Blocks)",
+    )
+    .unwrap()
+});
+
+/// Total weight of the transactions in the mempool.
+pub static MEMPOOL_WEIGHT: Lazy<IntGauge> =
+    Lazy::new(|| IntGauge::new("mempool_weight", "Mempool Weight").unwrap());
+
+/// Number of transactions in the mempool.
+pub static MEMPOOL_TX_COUNT: Lazy<IntGauge> =
+    Lazy::new(|| IntGauge::new("mempool_tx_count", "Mempool Transaction Count").unwrap());
+
+/// Time taken by `Storage::apply_block`.
+pub static APPLY_BLOCK_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::with_opts(HistogramOpts::new(
+        "apply_block_seconds",
+        "Block Application Latency (In Seconds)",
+    ))
+    .unwrap()
+});
+
+/// Time taken by a staker consensus round, from start to commit.
+pub static CONSENSUS_ROUND_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    Histogram::with_opts(
+        HistogramOpts::new(
+            "consensus_round_seconds",
+            "Staker Consensus Round Duration (In Seconds)",
+        )
+        .buckets(vec![1.0, 2.5, 5.0, 10.0, 15.0, 20.0, 30.0, 60.0, 120.0]),
+    )
+    .unwrap()
+});
+
+/// Number of RPC calls served, by method.
+pub static RPC_CALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    IntCounterVec::new(
+        Opts::new("rpc_calls_total", "RPC Calls Served"),
+        &["method"],
+    )
+    .unwrap()
+});
+
+/// Starts the Prometheus webserver, serving `/metrics` on the given address.
+pub async fn start_server(
+    netid: NetID,
+    listen_addr: SocketAddr,
+    storage: &Storage,
+) -> anyhow::Result<smol::Task<()>> {
+    let hostname = gethostname::gethostname().to_string_lossy().to_string();
+    let labels: HashMap<String, String> = [
+        ("hostname".to_string(), hostname),
+        ("network".to_string(), netid.to_string()),
+    ]
+    .into_iter()
+    .collect();
+    let registry = Registry::new_custom(Some("themelio_node".into()), Some(labels))?;
+    registry.register(Box::new(HIGHEST_BLOCK.clone()))?;
+    registry.register(Box::new(BLKSYNC_LAG.clone()))?;
+    registry.register(Box::new(MEMPOOL_WEIGHT.clone()))?;
+    registry.register(Box::new(MEMPOOL_TX_COUNT.clone()))?;
+    registry.register(Box::new(APPLY_BLOCK_SECONDS.clone()))?;
+    registry.register(Box::new(CONSENSUS_ROUND_SECONDS.clone()))?;
+    registry.register(Box::new(RPC_CALLS.clone()))?;
+    // otherwise only set once a new block arrives
+    HIGHEST_BLOCK.set(storage.highest_height().await.0 as i64);
+
+    let task = start_http_server(listen_addr, move |request_line| {
+        let registry = registry.clone();
+        async move { metrics_response(&request_line, &registry) }
+    })
+    .await?;
+    log::info!("serving prometheus metrics at http://{listen_addr}/metrics");
+    Ok(task)
+}
+
+/// Responds to a single HTTP request. Only `GET /metrics` is served; everything else is a 404.
+fn metrics_response(request_line: &str, registry: &Registry) -> anyhow::Result<HttpResponse> {
+    let encoder = TextEncoder::new();
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    Ok(if path == "/metrics" {
+        let mut body = vec![];
+        encoder.encode(&registry.gather(), &mut body)?;
+        HttpResponse {
+            status: "200 OK",
+            headers: vec![("Content-Type", encoder.format_type().into())],
+            body,
+        }
+    } else {
+        HttpResponse {
+            status: "404 Not Found",
+            headers: vec![("Content-Type", "text/plain".into())],
+            body: b"not found".to_vec(),
+        }
+    })
+}
+
+/// An [RpcService] wrapper that counts the calls to every method the inner service knows about.
+pub struct MeteredService<S: RpcService>(pub S);
+
+#[async_trait]
+impl<S: RpcService> RpcService for MeteredService<S> {
+    async fn respond(
+        &self,
+        method: &str,
+        params: Vec<serde_json::Value>,
+    ) -> Option<Result<serde_json::Value, ServerError>> {
+        let res = self.0.respond(method, params).await;
+        // only count methods that exist, so that garbage requests cannot blow up the label set
+        if res.is_some() {
+            RPC_CALLS.with_label_values(&[method]).inc();
+        }
+        res
+    }
+}
@@ -1,7 +1,23 @@
 mod blksync;
+mod ext_rpc;
+mod http;
 mod indexer;
-
-use crate::{node::blksync::attempt_blksync, storage::Storage};
+mod reputation;
+mod statesync;
+
+pub use ext_rpc::*;
+pub use indexer::IndexerError;
+pub use reputation::PeerReputation;
+
+use crate::{
+    node::{
+        blksync::{attempt_blksync, MAX_SYNC_PEERS},
+        http::start_http_gateway,
+        reputation::{PeerOutcome, PeerTable},
+        statesync::statesync_loop,
+    },
+    storage::{MeshaCas, Storage},
+};
 
 use anyhow::Context;
 use async_trait::async_trait;
@@ -9,17 +25,21 @@ use base64::Engine;
 use lru::LruCache;
 use melblkidx::{CoinInfo, Indexer};
 use melnet2::{wire::http::HttpBackhaul, Backhaul, Swarm};
-use novasmt::{CompressedProof, Database, InMemoryCas, Tree};
+use melstf::SealedState;
+use nanorpc::OrService;
+use novasmt::{CompressedProof, ContentAddrStore, Tree};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 
-use melstf::SmtMapping;
 use melstructs::{
-    AbbrBlock, Address, Block, BlockHeight, CoinID, ConsensusProof, NetID, Transaction, TxHash,
+    AbbrBlock, Address, Block, BlockHeight, Checkpoint, CoinID, ConsensusProof, NetID, Transaction,
+    TxHash,
 };
 use std::{
+    borrow::Cow,
     collections::BTreeMap,
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 use stdcode::StdcodeSerializeExt;
@@ -37,6 +57,7 @@ use self::indexer::WrappedIndexer;
 /// An actor implementing the node P2P protocol, common for both replicas and stakers..
 pub struct Node {
     _blksync_task: smol::Task<()>,
+    _http_task: Option<smol::Task<()>>,
 }
 
 impl Node {
@@ -49,48 +70,117 @@ impl Node {
         storage: Storage,
         index_coins: bool,
         swarm: Swarm<HttpBackhaul, NodeRpcClient>,
+        trust_checkpoint: Option<Checkpoint>,
+        http_listen: Option<SocketAddr>,
     ) -> anyhow::Result<Self> {
+        let reputation = PeerTable::default();
+        let rpc = NodeRpcImpl::start(
+            swarm.clone(),
+            reputation.clone(),
+            listen_addr,
+            netid,
+            storage.clone(),
+            index_coins,
+        )
+        .await?;
+        let _http_task = match http_listen {
+            Some(addr) => Some(start_http_gateway(rpc.clone(), addr).await?),
+            None => None,
+        };
+        let service = OrService::new(NodeRpcService(rpc.clone()), NodeExtRpcService(rpc));
+        #[cfg(feature = "metrics")]
+        let service = crate::metrics::MeteredService(service);
+
         // This is all we need to do since start_listen does not block.
         log::debug!("starting to listen at {}", listen_addr);
         swarm
             .start_listen(
                 listen_addr.to_string().into(),
                 advertise_addr.map(|addr| addr.to_string().into()),
-                NodeRpcService(
-                    NodeRpcImpl::start(
-                        swarm.clone(),
-                        listen_addr,
-                        netid,
-                        storage.clone(),
-                        index_coins,
-                    )
-                    .await?,
-                ),
+                service,
             )
             .await?;
 
-        let _blksync_task = smolscale::spawn(blksync_loop(netid, swarm, storage));
-        Ok(Self { _blksync_task })
+        let _blksync_task = smolscale::spawn(blksync_loop(
+            netid,
+            swarm,
+            reputation,
+            storage,
+            trust_checkpoint,
+        ));
+        Ok(Self {
+            _blksync_task,
+            _http_task,
+        })
     }
 }
 
-async fn blksync_loop(_netid: NetID, swarm: Swarm<HttpBackhaul, NodeRpcClient>, storage: Storage) {
+async fn blksync_loop(
+    _netid: NetID,
+    swarm: Swarm<HttpBackhaul, NodeRpcClient>,
+    reputation: PeerTable,
+    storage: Storage,
+    trust_checkpoint: Option<Checkpoint>,
+) {
+    if let Some(checkpoint) = trust_checkpoint {
+        statesync_loop(&swarm, &storage, &checkpoint).await;
+    }
     loop {
         let gap_time: Duration = Duration::from_secs_f64(fastrand::f64() * 1.0);
-        let routes = swarm.routes().await;
-        let random_peer = routes.first().cloned();
-        if let Some(peer) = random_peer {
-            log::trace!("picking peer {} out of {} peers", &peer, routes.len());
+        // routes come in random order, so peers with equal standing are picked at random
+        let routes = reputation.rank(swarm.routes().await);
+        // while we're synced, asking one peer whether there's anything new is enough; only once we're behind is it worth asking everyone
+        if let Some(peer) = routes.first() {
+            let peer_is_ahead = async {
+                let their_highest = swarm
+                    .connect(peer.clone())
+                    .await?
+                    .get_summary()
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .context("timed out getting summary")?
+                    .context("cannot get their highest block")?
+                    .height;
+                anyhow::Ok(their_highest > storage.highest_height().await)
+            };
+            match peer_is_ahead.await {
+                Ok(false) => {
+                    #[cfg(feature = "metrics")]
+                    crate::metrics::BLKSYNC_LAG.set(0);
+                    smol::Timer::after(gap_time).await;
+                    continue;
+                }
+                Ok(true) => {}
+                Err(err) => {
+                    log::warn!("cannot get the highest block of {}: {:?}", peer, err);
+                    reputation.record(peer, PeerOutcome::Timeout);
+                }
+            }
+        }
+        let mut peers = vec![];
+        for peer in routes.iter().take(MAX_SYNC_PEERS) {
             let fallible_part = async {
-                let client = swarm.connect(peer.clone()).await?;
+                // both protocols are served on the same listener, so one connection does for both
+                let transport = Arc::new(swarm.connect(peer.clone()).await?.0);
+                let client = NodeRpcClient::from(transport.clone());
+                let ext_client = NodeExtRpcClient::from(transport);
                 let addr: SocketAddr = peer.clone().to_string().parse()?;
-                let res = attempt_blksync(addr, &client, &storage).await?;
-                anyhow::Ok(res)
+                anyhow::Ok((addr, client, ext_client))
             };
             match fallible_part.await {
+                Ok(peer) => peers.push(peer),
                 Err(e) => {
-                    log::warn!("failed to blksync with {}: {:?}", peer, e);
-                    log::warn!("last state: {:?}", storage.highest_state().await.header());
+                    log::warn!("cannot connect to {}: {:?}", peer, e);
+                    reputation.record(peer, PeerOutcome::Timeout);
+                }
+            }
+        }
+        if !peers.is_empty() {
+            log::trace!("picking {} peers out of {}", peers.len(), routes.len());
+            match attempt_blksync(peers, &storage, &reputation).await {
+                Err(e) => {
+                    log::warn!("failed to blksync: {:?}", e);
+                    log::warn!("last state: {:?}", storage.highest_header().await);
                 }
                 Ok(blklen) => {
                     if blklen > 0 {
@@ -104,82 +194,94 @@ async fn blksync_loop(_netid: NetID, swarm: Swarm<HttpBackhaul, NodeRpcClient>,
 }
 
 // This struct is responsible for obtaining any "state" needed for the implementation of the RPC business logic.
+// It is cheaply cloneable, so that the same state can back both the melprot and the melnode-specific protocols.
+#[derive(Clone)]
 pub struct NodeRpcImpl {
     network: NetID,
     storage: Storage,
-    recent: Mutex<LruCache<TxHash, Instant>>,
-    summary: Mutex<LruCache<BlockHeight, StateSummary>>,
-    coin_smts: Mutex<LruCache<BlockHeight, Tree<InMemoryCas>>>,
+    recent: Arc<Mutex<LruCache<TxHash, Instant>>>,
+    summary: Arc<Mutex<LruCache<BlockHeight, StateSummary>>>,
     abbr_block_cache: moka::sync::Cache<BlockHeight, (AbbrBlock, ConsensusProof)>,
     swarm: Swarm<HttpBackhaul, NodeRpcClient>,
-    indexer: Option<WrappedIndexer>,
+    reputation: PeerTable,
+    indexer: Option<Arc<WrappedIndexer>>,
 }
 
 impl NodeRpcImpl {
     async fn start(
         swarm: Swarm<HttpBackhaul, NodeRpcClient>,
+        reputation: PeerTable,
         listen_addr: SocketAddr,
         network: NetID,
         storage: Storage,
         index_coins: bool,
     ) -> anyhow::Result<Self> {
         let indexer = if index_coins {
-            Some(WrappedIndexer::start(network, storage.clone(), listen_addr).await?)
+            Some(
+                WrappedIndexer::start(network, storage.clone(), listen_addr)
+                    .await?
+                    .into(),
+            )
         } else {
             None
         };
         Ok(Self {
             network,
             storage,
-            recent: LruCache::new(1000).into(),
-            coin_smts: LruCache::new(100).into(),
-            summary: LruCache::new(10).into(),
+            recent: Arc::new(LruCache::new(1000).into()),
+            summary: Arc::new(LruCache::new(10).into()),
             swarm,
+            reputation,
             abbr_block_cache: moka::sync::Cache::new(1000),
             indexer,
         })
     }
 
-    async fn get_coin_tree(&self, height: BlockHeight) -> anyhow::Result<Tree<InMemoryCas>> {
-        let otree = self.coin_smts.lock().get(&height).cloned();
-        if let Some(v) = otree {
-            Ok(v)
-        } else {
-            let state = self
-                .storage
-                .get_state(height)
-                .await
-                .context(format!("block {} not confirmed yet", height))?;
-            let mut mm = SmtMapping::new(
-                Database::new(InMemoryCas::default())
-                    .get_tree(Default::default())
-                    .unwrap(),
-            );
+    async fn get_coin_tree(&self, height: BlockHeight) -> anyhow::Result<Tree<MeshaCas>> {
+        self.storage
+            .get_transactions_smt(height)
+            .await
+            .context(format!("block {} not confirmed yet", height))
+    }
 
-            let transactions = state.to_block().transactions;
-            for tx in transactions.iter() {
-                mm.insert(tx.hash_nosigs(), tx.clone());
-            }
-            self.coin_smts.lock().put(height, mm.mapping.clone());
-            Ok(mm.mapping)
-        }
+    /// Gets the coin indexer, once it has caught up with the highest block. Gives up if that takes too long, rather than holding up the RPC forever.
+    async fn get_indexer(&self) -> Result<&Indexer, IndexerError> {
+        let indexer = self.indexer.as_ref().ok_or(IndexerError::Disabled)?.inner();
+        let height = self.storage.highest_height().await;
+        indexer::wait_for_height(|| indexer.max_height(), height, INDEXER_WAIT_TIMEOUT).await?;
+        Ok(indexer)
     }
+}
 
-    async fn get_indexer(&self) -> Option<&Indexer> {
-        if let Some(indexer) = self.indexer.as_ref() {
-            let indexer = indexer.inner();
-            let height = self.storage.highest_height().await;
-            while indexer.max_height() < height {
-                log::warn!("waiting for {height} to be available at the indexer...");
-                smol::Timer::after(Duration::from_secs(1)).await;
-            }
-            Some(indexer)
-        } else {
-            None
+/// Gets the value at a key of one of the SMTs of a state, together with a proof against the root in the header. Since rebuilding the stakes SMT and loading the transactions SMT are expensive, they're passed in by the caller, which only needs to provide them for [Substate::Stakes] and [Substate::Transactions] keys respectively; `None` is returned if a needed one is missing.
+fn smt_branch(
+    state: &SealedState<MeshaCas>,
+    stakes_smt: Option<&Tree<impl ContentAddrStore>>,
+    ctree: Option<&Tree<MeshaCas>>,
+    elem: Substate,
+    key: HashVal,
+) -> Option<(Vec<u8>, CompressedProof)> {
+    let coins_smt = state.raw_coins_smt();
+    let history_smt = state.raw_history_smt();
+    let pools_smt = state.raw_pools_smt();
+
+    let (v, proof) = match elem {
+        Substate::Coins => coins_smt.get_with_proof(key.0),
+        Substate::History => history_smt.get_with_proof(key.0),
+        Substate::Pools => pools_smt.get_with_proof(key.0),
+        Substate::Stakes => {
+            // proven against the TIP-911 stake set, whose root is the header's `stakes_hash`
+            let (v, proof) = stakes_smt?.get_with_proof(key.0);
+            (Cow::Owned(v.to_vec()), proof)
         }
-    }
+        Substate::Transactions => ctree?.get_with_proof(key.0),
+    };
+    Some((v.to_vec(), proof.compress()))
 }
 
+/// How long RPCs wait for the coin indexer to catch up with the highest block.
+const INDEXER_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Global TCP backhaul for node connections
 static TCP_BACKHAUL: Lazy<HttpBackhaul> = Lazy::new(HttpBackhaul::new);
 
@@ -211,18 +313,29 @@ impl NodeRpcProtocol for NodeRpcImpl {
             start.elapsed(),
         );
 
-        let routes = self.swarm.routes().await;
+        let routes = self.reputation.rank(self.swarm.routes().await);
         for neigh in routes.iter().take(16).cloned() {
             log::debug!("about to broadcast txhash {} to {neigh}", tx.hash_nosigs());
             let tx = tx.clone();
+            let reputation = self.reputation.clone();
             smolscale::spawn(async move {
-                let conn = TCP_BACKHAUL.connect(neigh).await?;
-                NodeRpcClient(conn)
-                    .send_tx(tx)
-                    .timeout(Duration::from_secs(10))
-                    .await
-                    .context("oh no")???;
-                anyhow::Ok(())
+                let fallible_part = async {
+                    let conn = TCP_BACKHAUL.connect(neigh.clone()).await?;
+                    let res = NodeRpcClient(conn)
+                        .send_tx(tx)
+                        .timeout(Duration::from_secs(10))
+                        .await
+                        .context("timed out broadcasting transaction")??;
+                    anyhow::Ok(res)
+                };
+                // the peer refusing the transaction, say because it already has it, isn't held against it
+                match fallible_part.await {
+                    Ok(_) => reputation.record(neigh, PeerOutcome::Success),
+                    Err(e) => {
+                        log::debug!("cannot broadcast transaction to {neigh}: {:?}", e);
+                        reputation.record(neigh, PeerOutcome::Timeout);
+                    }
+                }
             })
             .detach();
         }
@@ -235,17 +348,14 @@ impl NodeRpcProtocol for NodeRpcImpl {
             return Some(c);
         }
         log::trace!("handling get_abbr_block({})", height);
-        let block = self.storage.get_block(height).await?;
-        let proof = self.storage.get_consensus(height).await?;
-        let summ = (block.abbreviate(), proof);
+        let summ = self.storage.get_abbr_block(height).await?;
         self.abbr_block_cache.insert(height, summ.clone());
         Some(summ)
     }
 
     async fn get_summary(&self) -> StateSummary {
         log::trace!("handling get_summary()");
-        let highest = self.storage.highest_state().await;
-        let header = highest.header();
+        let header = self.storage.highest_header().await;
         let res = self.summary.lock().get(&header.height).cloned();
         if let Some(res) = res {
             res
@@ -300,7 +410,9 @@ impl NodeRpcProtocol for NodeRpcImpl {
                         height += BlockHeight(1);
                     }
                     _ => {
+                        // the block was probably pruned from under us
                         log::warn!("no proof stored for height {}", height);
+                        break;
                     }
                 }
             } else if accum.is_empty() {
@@ -311,6 +423,9 @@ impl NodeRpcProtocol for NodeRpcImpl {
             }
         }
 
+        if accum.is_empty() {
+            return None;
+        }
         let compressed = lz4_flex::compress_prepend_size(&(accum, proof_accum).stdcode());
         Some(base64::engine::general_purpose::STANDARD_NO_PAD.encode(compressed))
     }
@@ -322,24 +437,22 @@ impl NodeRpcProtocol for NodeRpcImpl {
         key: HashVal,
     ) -> Option<(Vec<u8>, CompressedProof)> {
         log::trace!("handling get_smt_branch({}, {:?})", height, elem);
-        let state = self.storage.get_state(height).await?;
-        let ctree = self.get_coin_tree(height).await.ok()?;
-        let coins_smt = state.raw_coins_smt();
-        let history_smt = state.raw_history_smt();
-        let pools_smt = state.raw_pools_smt();
-
-        let (v, proof) = match elem {
-            Substate::Coins => coins_smt.get_with_proof(key.0),
-            Substate::History => history_smt.get_with_proof(key.0),
-            Substate::Pools => pools_smt.get_with_proof(key.0),
-            Substate::Stakes => todo!("no longer relevant"),
-            Substate::Transactions => ctree.get_with_proof(key.0),
+        let state = self.storage.get_state(height).await.ok()?;
+        let stakes_smt = if matches!(elem, Substate::Stakes) {
+            Some(state.raw_stakes().pre_tip911())
+        } else {
+            None
+        };
+        let ctree = if matches!(elem, Substate::Transactions) {
+            Some(self.get_coin_tree(height).await.ok()?)
+        } else {
+            None
         };
-        Some((v.to_vec(), proof.compress()))
+        smt_branch(&state, stakes_smt.as_ref(), ctree.as_ref(), elem, key)
     }
 
     async fn get_stakers_raw(&self, height: BlockHeight) -> Option<BTreeMap<HashVal, Vec<u8>>> {
-        let state = self.storage.get_state(height).await?;
+        let state = self.storage.get_state(height).await.ok()?;
         // Note, the returned HashVal is >> HASHED AGAIN << because this is supposed to be compatible with the old SmtMapping encoding, where the key to the `stakes` SMT is the *hash of the transaction hash* due to a quirk.
         Some(
             state
@@ -351,7 +464,7 @@ impl NodeRpcProtocol for NodeRpcImpl {
     }
 
     async fn get_some_coins(&self, height: BlockHeight, covhash: Address) -> Option<Vec<CoinID>> {
-        let indexer = self.get_indexer().await?;
+        let indexer = self.get_indexer().await.ok()?;
         let coins: Vec<CoinID> = indexer
             .query_coins()
             .covhash(covhash)
@@ -372,7 +485,7 @@ impl NodeRpcProtocol for NodeRpcImpl {
     ) -> Option<Vec<CoinChange>> {
         log::debug!("get_coin_changes({height}, {covhash})");
         self.storage.get_block(height).await?;
-        let indexer = self.get_indexer().await?;
+        let indexer = self.get_indexer().await.ok()?;
         // get coins 1 block below the given height
         let deleted_coins: Vec<CoinInfo> = indexer
             .query_coins()
@@ -406,11 +519,11 @@ impl NodeRpcProtocol for NodeRpcImpl {
         // which coins got deleted in before coins?
         let deleted: Vec<CoinChange> = deleted_coins
             .iter()
-            .map(|coin| {
-                CoinChange::Delete(
+            .filter_map(|coin| {
+                Some(CoinChange::Delete(
                     CoinID::new(coin.create_txhash, coin.create_index),
-                    coin.spend_info.unwrap().spend_txhash,
-                )
+                    coin.spend_info?.spend_txhash,
+                ))
             })
             .collect();
 
@@ -418,7 +531,7 @@ impl NodeRpcProtocol for NodeRpcImpl {
     }
 
     async fn get_coin_spend(&self, coin: CoinID) -> Option<CoinSpendStatus> {
-        let indexer = self.get_indexer().await?;
+        let indexer = self.get_indexer().await.ok()?;
 
         let spend_info = indexer
             .query_coins()
@@ -1,95 +1,321 @@
-use crate::storage::Storage;
+use crate::{
+    node::{
+        reputation::{PeerOutcome, PeerTable},
+        NodeExtRpcClient,
+    },
+    storage::{verify_blocks, ApplyBlockError, Storage, VerifiedBlock},
+};
 use anyhow::Context;
 use base64::Engine;
-use futures_util::stream::{StreamExt, TryStreamExt};
+use futures_util::stream::{FuturesUnordered, StreamExt, TryStreamExt};
 use melprot::NodeRpcClient;
 use melstructs::{Block, BlockHeight, ConsensusProof};
 use smol_timeout::TimeoutExt;
 use std::{
+    collections::BTreeMap,
     net::SocketAddr,
+    ops::Range,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-/// Attempts a sync using the given given node client.
-pub async fn attempt_blksync(
+/// The most peers blocks are downloaded from at once.
+pub const MAX_SYNC_PEERS: usize = 8;
+
+/// How many blocks a single download assignment covers.
+const CHUNK_BLOCKS: u64 = 100;
+
+/// How many chunks may be downloaded ahead of the block being applied.
+const MAX_CHUNKS_AHEAD: u64 = 32;
+
+/// How many slow or failed downloads a peer may cause before it's dropped from a sync.
+const MAX_STRIKES: usize = 3;
+
+/// A peer taking part in a sync.
+struct SyncPeer {
     addr: SocketAddr,
-    client: &NodeRpcClient,
+    client: Arc<NodeRpcClient>,
+    /// The lowest block the peer has in full; pruned peers, or peers started from a trusted checkpoint, can't serve anything older.
+    lowest: BlockHeight,
+    highest: BlockHeight,
+    strikes: usize,
+    busy: bool,
+}
+
+/// Why downloading a chunk from a peer failed.
+enum PeerFault {
+    /// The peer was slow or unreachable, which may be temporary.
+    Slow(anyhow::Error),
+    /// The peer sent data that it shouldn't have.
+    Bad(anyhow::Error),
+    /// The peer doesn't have some of the blocks, for instance because it pruned them since telling us what it has. That's not misbehavior, but there's no point asking it again.
+    Missing(anyhow::Error),
+}
+
+/// The chunks of the missing range that still need to be handed out to peers.
+struct ChunkQueue {
+    /// Chunks that failed and need to be handed out again, earliest first.
+    retry: BTreeMap<u64, Range<u64>>,
+    /// Start of the next chunk that was never handed out.
+    next_fresh: u64,
+    /// End of the missing range.
+    end: u64,
+}
+
+impl ChunkQueue {
+    /// Takes the earliest chunk that a peer with the given lowest and highest blocks can serve, without going more than `horizon` blocks ahead.
+    fn take(
+        &mut self,
+        peer_lowest: BlockHeight,
+        peer_highest: BlockHeight,
+        horizon: u64,
+    ) -> Option<Range<u64>> {
+        let retry = self
+            .retry
+            .values()
+            .find(|chunk| chunk.start >= peer_lowest.0 && chunk.end <= peer_highest.0 + 1)
+            .cloned();
+        if let Some(chunk) = retry {
+            self.retry.remove(&chunk.start);
+            return Some(chunk);
+        }
+        if self.next_fresh < peer_lowest.0 {
+            return None;
+        }
+        let end = (self.next_fresh + CHUNK_BLOCKS)
+            .min(self.end)
+            .min(peer_highest.0 + 1)
+            .min(horizon);
+        if end <= self.next_fresh {
+            return None;
+        }
+        let chunk = self.next_fresh..end;
+        self.next_fresh = end;
+        Some(chunk)
+    }
+
+    /// Puts back a chunk that must be handed out again.
+    fn give_back(&mut self, chunk: Range<u64>) {
+        self.retry.insert(chunk.start, chunk);
+    }
+}
+
+/// Attempts a sync using the given node clients. The missing blocks are split into chunks, downloaded from all the peers concurrently, and applied in order. Peers only get chunks within the range of blocks they have. Peers that send bad data are dropped from the sync right away, and peers that are slow are dropped after a few strikes. Either way, what happened is recorded in the peer table.
+pub async fn attempt_blksync(
+    peers: Vec<(SocketAddr, NodeRpcClient, NodeExtRpcClient)>,
     storage: &Storage,
+    reputation: &PeerTable,
 ) -> anyhow::Result<usize> {
     if std::env::var("MELNODE_OLD_BLKSYNC").is_ok() {
-        return attempt_blksync_legacy(addr, client, storage).await;
+        let (addr, client, _) = peers.into_iter().next().context("no peers to sync with")?;
+        return attempt_blksync_legacy(addr, &client, storage).await;
     }
 
-    let their_highest = client
-        .get_summary()
-        .timeout(Duration::from_secs(5))
-        .await
-        .context("timed out getting summary")?
-        .context("cannot get their highest block")?
-        .height;
+    let summaries = futures_util::future::join_all(peers.into_iter().map(
+        |(addr, client, ext_client)| async move {
+            let summary = client.get_summary().timeout(Duration::from_secs(5)).await;
+            // older nodes can't tell, so they're assumed to have every block
+            let lowest = ext_client
+                .get_lowest_height()
+                .timeout(Duration::from_secs(5))
+                .await
+                .and_then(|res| res.ok())
+                .unwrap_or_default();
+            (addr, client, summary, lowest)
+        },
+    ))
+    .await;
+    let mut peers = vec![];
+    for (addr, client, summary, lowest) in summaries {
+        match summary {
+            Some(Ok(summary)) => peers.push(SyncPeer {
+                addr,
+                client: Arc::new(client),
+                lowest,
+                highest: summary.height,
+                strikes: 0,
+                busy: false,
+            }),
+            Some(Err(err)) => {
+                log::warn!("cannot get the highest block of {addr}: {:?}", err);
+                reputation.record(addr, PeerOutcome::Timeout);
+            }
+            None => {
+                log::warn!("timed out getting the highest block of {addr}");
+                reputation.record(addr, PeerOutcome::Timeout);
+            }
+        }
+    }
+    let their_highest = peers
+        .iter()
+        .map(|peer| peer.highest)
+        .max()
+        .context("no peer told us its highest block")?;
 
     let my_highest = storage.highest_height().await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::BLKSYNC_LAG.set(their_highest.0.saturating_sub(my_highest.0) as i64);
     if their_highest <= my_highest {
         return Ok(0);
     }
 
+    let mut queue = ChunkQueue {
+        retry: BTreeMap::new(),
+        next_fresh: my_highest.0 + 1,
+        end: their_highest.0 + 1,
+    };
+    let mut next_apply = my_highest.0 + 1;
+    // downloaded chunks waiting for their turn, by start height, with the peer they came from
+    let mut downloaded: BTreeMap<u64, (usize, Range<u64>, Vec<VerifiedBlock>)> = BTreeMap::new();
+    let mut in_flight = FuturesUnordered::new();
     let mut num_blocks_applied: usize = 0;
-    let my_highest: u64 = my_highest.0 + 1;
 
-    let mut height = BlockHeight(my_highest);
-    while height <= their_highest {
-        let start = Instant::now();
+    while next_apply <= their_highest.0 {
+        // hand out chunks to every idle peer still in good standing
+        let horizon = next_apply + MAX_CHUNKS_AHEAD * CHUNK_BLOCKS;
+        for (idx, peer) in peers.iter_mut().enumerate() {
+            if peer.busy || peer.strikes >= MAX_STRIKES {
+                continue;
+            }
+            if let Some(chunk) = queue.take(peer.lowest, peer.highest, horizon) {
+                log::debug!("getting blocks {:?} from {}", chunk, peer.addr);
+                peer.busy = true;
+                let client = peer.client.clone();
+                in_flight.push(smolscale::spawn(async move {
+                    let start = Instant::now();
+                    let res = fetch_chunk(&client, chunk.clone()).await;
+                    (idx, chunk, res, start.elapsed())
+                }));
+            }
+        }
 
-        log::debug!("gonna get compressed blocks from {addr}...");
-        let compressed_blocks = client
-            .get_lz4_blocks(height, 500_000)
-            .timeout(Duration::from_secs(30))
+        let (idx, chunk, res, elapsed) = in_flight
+            .next()
             .await
-            .context("timeout while getting compressed blocks")?
-            .context("failed to get compressed blocks")?;
-        log::debug!("got compressed blocks!");
+            .context("ran out of peers to sync from")?;
+        let peer = &mut peers[idx];
+        peer.busy = false;
+        match res {
+            Ok(_) if peer.strikes >= MAX_STRIKES => {
+                // the peer was dropped while this download was in flight
+                queue.give_back(chunk);
+            }
+            Ok(blocks) => {
+                log::info!(
+                    "fully resolved blocks {:?} from peer {} in {:.2}ms",
+                    chunk,
+                    peer.addr,
+                    elapsed.as_secs_f64() * 1000.0
+                );
+                reputation.record(peer.addr, PeerOutcome::Success);
+                downloaded.insert(chunk.start, (idx, chunk, blocks));
+            }
+            Err(fault) => {
+                let (err, strikes, outcome) = match fault {
+                    PeerFault::Slow(err) => (err, peer.strikes + 1, Some(PeerOutcome::Timeout)),
+                    PeerFault::Bad(err) => (err, MAX_STRIKES, Some(PeerOutcome::Invalid)),
+                    PeerFault::Missing(err) => (err, MAX_STRIKES, None),
+                };
+                log::warn!(
+                    "failed to get blocks {:?} from {}: {:?}",
+                    chunk,
+                    peer.addr,
+                    err
+                );
+                peer.strikes = strikes;
+                if let Some(outcome) = outcome {
+                    reputation.record(peer.addr, outcome);
+                }
+                queue.give_back(chunk);
+            }
+        }
 
-        let (blocks, cproofs): (Vec<Block>, Vec<ConsensusProof>) = match compressed_blocks {
-            Some(compressed) => {
-                // decode base64 first
-                let compressed_base64 = base64::engine::general_purpose::STANDARD_NO_PAD
-                    .decode(compressed.as_bytes())?;
+        // apply whatever is now contiguous with our highest block
+        while let Some((idx, chunk, blocks)) = downloaded.remove(&next_apply) {
+            for block in blocks {
+                if let Err(err) = storage.apply_verified_block(block).await {
+                    if storage.highest_height().await.0 >= next_apply {
+                        // somebody else, like the staker, applied this block first
+                        return Ok(num_blocks_applied);
+                    }
+                    let err = match err {
+                        ApplyBlockError::Invalid(err) => err,
+                        // not the peer's fault, so give up on this sync without blaming anyone
+                        ApplyBlockError::Local(err) => {
+                            return Err(err.context(format!("cannot apply block {next_apply}")))
+                        }
+                    };
+                    log::warn!(
+                        "could not apply block {next_apply} from {}: {:?}",
+                        peers[idx].addr,
+                        err
+                    );
+                    peers[idx].strikes = MAX_STRIKES;
+                    reputation.record(peers[idx].addr, PeerOutcome::Invalid);
+                    queue.give_back(next_apply..chunk.end);
+                    break;
+                }
+                num_blocks_applied += 1;
+                next_apply += 1;
+            }
+        }
+    }
 
-                // decompress
-                let decompressed = lz4_flex::decompress_size_prepended(&compressed_base64)?;
+    Ok(num_blocks_applied)
+}
 
-                stdcode::deserialize::<(Vec<Block>, Vec<ConsensusProof>)>(&decompressed)?
+/// Downloads a chunk of consecutive blocks from a peer, verifying their signatures and that they chain together before they're queued for applying.
+async fn fetch_chunk(
+    client: &NodeRpcClient,
+    chunk: Range<u64>,
+) -> Result<Vec<VerifiedBlock>, PeerFault> {
+    let mut blocks = vec![];
+    let mut height = chunk.start;
+    while height < chunk.end {
+        let compressed = client
+            .get_lz4_blocks(BlockHeight(height), 500_000)
+            .timeout(Duration::from_secs(30))
+            .await
+            .context("timeout while getting compressed blocks")
+            .map_err(PeerFault::Slow)?
+            .context("failed to get compressed blocks")
+            .map_err(PeerFault::Slow)?
+            .context("peer does not have the blocks")
+            .map_err(PeerFault::Missing)?;
+        let (batch, cproofs) = decode_lz4_blocks(&compressed).map_err(PeerFault::Bad)?;
+        if batch.is_empty() {
+            return Err(PeerFault::Missing(anyhow::anyhow!("peer sent no blocks")));
+        }
+        for (block, cproof) in batch.into_iter().zip(cproofs) {
+            if height == chunk.end {
+                break;
             }
-            _ => anyhow::bail!("missing block {height}"),
-        };
-
-        let mut last_applied_height = height;
-        log::info!(
-            "fully resolved blocks {}..{} from peer {} in {:.2}ms",
-            blocks.first().map(|b| b.header.height).unwrap_or_default(),
-            blocks.last().map(|b| b.header.height).unwrap_or_default(),
-            addr,
-            start.elapsed().as_secs_f64() * 1000.0
-        );
-        for (block, cproof) in blocks.iter().zip(cproofs) {
-            // validate before applying
-            if block.header.height != last_applied_height {
-                anyhow::bail!("wanted block {}, but got {}", height, block.header.height);
+            if block.header.height.0 != height {
+                return Err(PeerFault::Bad(anyhow::anyhow!(
+                    "wanted block {}, but got {}",
+                    height,
+                    block.header.height
+                )));
             }
-
-            storage
-                .apply_block(block.clone(), cproof)
-                .await
-                .context("could not apply a resolved block")?;
-            num_blocks_applied += 1;
-
-            last_applied_height += BlockHeight(1);
+            blocks.push((block, cproof));
+            height += 1;
         }
-
-        height += BlockHeight(blocks.len() as u64);
     }
+    verify_blocks(blocks).await.map_err(PeerFault::Bad)
+}
 
-    Ok(num_blocks_applied)
+/// Decodes the response of `get_lz4_blocks`.
+fn decode_lz4_blocks(compressed: &str) -> anyhow::Result<(Vec<Block>, Vec<ConsensusProof>)> {
+    // decode base64 first
+    let compressed_base64 =
+        base64::engine::general_purpose::STANDARD_NO_PAD.decode(compressed.as_bytes())?;
+
+    // decompress
+    let decompressed = lz4_flex::decompress_size_prepended(&compressed_base64)?;
+
+    Ok(stdcode::deserialize::<(Vec<Block>, Vec<ConsensusProof>)>(
+        &decompressed,
+    )?)
 }
 
 /// Attempts a sync using the given given node client, in a legacy fashion.
@@ -106,6 +332,8 @@ pub async fn attempt_blksync_legacy(
         .context("cannot get their highest block")?
         .height;
     let my_highest = storage.highest_height().await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::BLKSYNC_LAG.set(their_highest.0.saturating_sub(my_highest.0) as i64);
     if their_highest <= my_highest {
         return Ok(0);
     }
@@ -0,0 +1,580 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use base64::Engine;
+use futures_util::Stream;
+use melblkidx::{CoinInfo, Indexer};
+use melprot::{CoinChange, Substate};
+use melstructs::{
+    Address, BlockHeight, CoinDataHeight, CoinID, CoinValue, Denom, Header, StakeDoc, Transaction,
+    TxHash,
+};
+use nanorpc::{nanorpc_derive, RpcTransport};
+use novasmt::{CompressedProof, ContentAddrStore};
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
+use stdcode::StdcodeSerializeExt;
+use tmelcrypt::{HashVal, Hashable};
+
+use crate::storage::{vote_weights, SnapshotMeta, StateError};
+
+use super::{smt_branch, IndexerError, NodeRpcImpl};
+
+/// How long long-polling calls wait for a new block before giving up.
+const LONG_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The most headers returned by a single [NodeExtRpcProtocol::wait_headers] call.
+const MAX_HEADERS: usize = 1000;
+
+/// The most addresses a single [NodeExtRpcProtocol::get_address_coin_changes] call may ask about.
+const MAX_ADDRESSES: usize = 100;
+
+/// The most coin changes a single [NodeExtRpcProtocol::get_address_coin_changes] call returns, give or take one block's worth.
+const MAX_COIN_CHANGES: usize = 10_000;
+
+/// The most heights a single [NodeExtRpcProtocol::get_address_coin_changes] call looks through.
+const MAX_COIN_CHANGE_HEIGHTS: u64 = 1000;
+
+/// The most SMT nodes a single [NodeExtRpcProtocol::get_lz4_smt_nodes] call may ask for.
+const MAX_SMT_NODES: usize = 1000;
+
+/// The most coins a single [NodeExtRpcProtocol::get_proven_coins] call returns; wallets with more coins are paged through.
+const MAX_PROVEN_COINS: usize = 1000;
+
+/// The most keys a single [NodeExtRpcProtocol::get_smt_branches] call may ask about.
+const MAX_SMT_KEYS: usize = 1000;
+
+/// RPC endpoints specific to melnode, served alongside [melprot::NodeRpcProtocol] on the same listener.
+#[nanorpc_derive]
+#[async_trait]
+pub trait NodeExtRpcProtocol {
+    /// Looks up a confirmed transaction by its hash. Returns `None` if the node has no record of it.
+    async fn get_tx(&self, txhash: TxHash) -> Option<TxLookup>;
+
+    /// Reports what happened to a transaction previously sent to this node.
+    async fn get_tx_status(&self, txhash: TxHash) -> TxStatus;
+
+    /// Lists the pending transactions in the mempool that create coins for, or spend coins from, the given address.
+    async fn get_pending_txx(&self, address: Address) -> Vec<Transaction>;
+
+    /// Long-polls for a block above the given height, returning the header of the new highest block. Returns `None` if no such block arrived within the server's timeout; clients should then simply call again.
+    async fn wait_new_block(&self, after: BlockHeight) -> Option<Header>;
+
+    /// Long-polls for blocks above the given height, returning the headers of up to `limit` consecutive blocks following it. Returns an empty list if no new block arrived within the server's timeout, and fails if the node doesn't have the header right after `after`, e.g. because it synced from a later checkpoint. Calling this in a loop gives a stream of headers; see [NodeExtRpcClient::header_stream].
+    async fn wait_headers(
+        &self,
+        after: BlockHeight,
+        limit: usize,
+    ) -> Result<Vec<Header>, StateError>;
+
+    /// Gets the coin changes of a set of addresses over the inclusive height range `start..=end`, ordered by height. About `limit` changes are returned per call, never splitting a height across pages, and each call only looks through a window of heights starting at `start`; if the page stops short of `end`, [CoinChangePage::next] says where to `start` the next call. Pages may be empty even when there are more changes further on, so keep calling until `next` is `None`. Fails if the node doesn't index coins, or if too many addresses are given.
+    async fn get_address_coin_changes(
+        &self,
+        addresses: Vec<Address>,
+        start: BlockHeight,
+        end: BlockHeight,
+        limit: usize,
+    ) -> Result<CoinChangePage, CoinQueryError>;
+
+    /// Gets the balance of an address as of the given height, in every denomination it holds. Fails if the node doesn't index coins, or doesn't have that height yet.
+    async fn get_balance(
+        &self,
+        address: Address,
+        height: BlockHeight,
+    ) -> Result<Vec<Balance>, CoinQueryError>;
+
+    /// Gets everything about the state at the given height besides its SMT nodes, for nodes bootstrapping from a trusted checkpoint. Returns `None` if this node doesn't have that state.
+    async fn get_snapshot_meta(&self, height: BlockHeight) -> Option<SnapshotMeta>;
+
+    /// Gets raw SMT nodes by hash, as the base64-encoded, lz4-compressed stdcode of a list of nodes in the order asked for. Nodes this node doesn't have come back empty. Returns `None` if too many nodes are asked for.
+    async fn get_lz4_smt_nodes(&self, hashes: Vec<HashVal>) -> Option<String>;
+
+    /// Gets the coins of an address that are unspent as of the given height, each with a proof against the `coins_hash` of that height's header, so that light clients can check a whole wallet in a few calls. Coins are returned in [CoinID] order, starting after the `after` coin if given; if the address has more coins than fit in one page, [ProvenCoinPage::next] says what to pass as `after` next. Fails if the node doesn't index coins, or doesn't have that height.
+    async fn get_proven_coins(
+        &self,
+        address: Address,
+        height: BlockHeight,
+        after: Option<CoinID>,
+    ) -> Result<ProvenCoinPage, CoinQueryError>;
+
+    /// Gets many SMT branches at a height at once, as if calling [melprot::NodeRpcProtocol::get_smt_branch] for every (substate, key), but loading the state, and building the stakes and transactions SMTs if needed, only once. The values and proofs come back in the order asked for. Returns `None` if the node doesn't have that height, or if too many keys are asked for.
+    async fn get_smt_branches(
+        &self,
+        height: BlockHeight,
+        keys: Vec<(Substate, HashVal)>,
+    ) -> Option<Vec<(Vec<u8>, CompressedProof)>>;
+
+    /// Lists the stakers whose votes count for the block after the given height, each stake doc with the voting weight of its public key and a proof against the `stakes_hash` of that height's header. This is what [melprot::NodeRpcProtocol::get_stakers_raw] exposes, but checkable by light clients. Returns `None` if the node doesn't have that height.
+    async fn get_active_stakers(&self, height: BlockHeight) -> Option<Vec<ProvenStake>>;
+
+    /// Gets the lowest height this node has the full block for, and so can serve through [melprot::NodeRpcProtocol::get_lz4_blocks]. Together with the height in [melprot::NodeRpcProtocol::get_summary], this is the range of blocks others can sync from this node, which doesn't start at genesis if the node prunes old blocks or started from a trusted checkpoint.
+    async fn get_lowest_height(&self) -> BlockHeight;
+}
+
+impl<T: RpcTransport> NodeExtRpcClient<T> {
+    /// Returns an endless stream of the headers of all blocks after the given height, in order, by repeatedly long-polling [NodeExtRpcProtocol::wait_headers]. The stream ends after the first error.
+    pub fn header_stream(
+        &self,
+        after: BlockHeight,
+    ) -> impl Stream<Item = Result<Header, HeaderStreamError<T::Error>>> + '_ {
+        let state = (after, Vec::<Header>::new().into_iter(), false);
+        futures_util::stream::unfold(state, move |(mut after, mut buffered, failed)| async move {
+            if failed {
+                return None;
+            }
+            loop {
+                if let Some(header) = buffered.next() {
+                    after = header.height;
+                    return Some((Ok(header), (after, buffered, false)));
+                }
+                match self.wait_headers(after, MAX_HEADERS).await {
+                    Ok(Ok(headers)) => buffered = headers.into_iter(),
+                    Ok(Err(err)) => return Some((Err(err.into()), (after, buffered, true))),
+                    Err(err) => return Some((Err(err.into()), (after, buffered, true))),
+                }
+            }
+        })
+    }
+}
+
+/// Why a [NodeExtRpcClient::header_stream] ended.
+#[derive(thiserror::Error, Debug)]
+pub enum HeaderStreamError<E> {
+    #[error(transparent)]
+    Rpc(#[from] NodeExtRpcError<E>),
+    /// The node doesn't have the next header.
+    #[error(transparent)]
+    State(#[from] StateError),
+}
+
+/// Why a query over the coin indexer could not be answered.
+#[derive(thiserror::Error, Debug, Clone, Serialize, Deserialize)]
+pub enum CoinQueryError {
+    #[error(transparent)]
+    Indexer(#[from] IndexerError),
+    #[error(transparent)]
+    State(#[from] StateError),
+    #[error("at most {0} addresses can be asked about at once")]
+    TooManyAddresses(usize),
+}
+
+/// A change in the coins owned by an address.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AddressCoinChange {
+    pub height: BlockHeight,
+    pub address: Address,
+    pub change: CoinChange,
+}
+
+/// A page of [AddressCoinChange]s.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CoinChangePage {
+    pub changes: Vec<AddressCoinChange>,
+    /// The height to start from to get the next page, if there is one.
+    pub next: Option<BlockHeight>,
+}
+
+/// How much of a particular denomination an address holds.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Balance {
+    pub denom: Denom,
+    pub total: CoinValue,
+    /// Number of unspent coins making up the total.
+    pub coin_count: u64,
+}
+
+/// An unspent coin, with its inclusion proof.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProvenCoin {
+    pub coin_id: CoinID,
+    pub coin_data: CoinDataHeight,
+    /// Proof against the `coins_hash` of the block header, keyed by the hash of the stdcode-encoded [CoinID].
+    pub proof: CompressedProof,
+}
+
+/// A page of [ProvenCoin]s.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProvenCoinPage {
+    pub coins: Vec<ProvenCoin>,
+    /// The coin to start after to get the next page, if there is one.
+    pub next: Option<CoinID>,
+}
+
+/// An active stake, with its inclusion proof.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProvenStake {
+    pub txhash: TxHash,
+    pub stake_doc: StakeDoc,
+    /// Voting weight of the stake doc's public key, counting all of its active stakes.
+    pub weight: u128,
+    /// Proof of the stdcode-encoded [StakeDoc] against the `stakes_hash` of the block header, keyed by the hash of the [TxHash].
+    pub proof: CompressedProof,
+}
+
+/// The status of a transaction, as far as this node knows.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Waiting in the mempool.
+    Pending,
+    /// Confirmed at the given height.
+    Confirmed(BlockHeight),
+    /// Recently dropped from the mempool without being confirmed, for the given reason.
+    Dropped(String),
+    /// Never seen, or dropped too long ago to remember.
+    Unknown,
+}
+
+/// A confirmed transaction, where it was confirmed, and its inclusion proof.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TxLookup {
+    pub transaction: Transaction,
+    pub height: BlockHeight,
+    /// Index of the transaction within its block's transactions, sorted by hash.
+    pub index: u32,
+    /// Proof against the `transactions_hash` of the block header, keyed by the hash of the stdcode-encoded [TxHash].
+    pub proof: CompressedProof,
+}
+
+#[async_trait]
+impl NodeExtRpcProtocol for NodeRpcImpl {
+    async fn get_tx(&self, txhash: TxHash) -> Option<TxLookup> {
+        log::trace!("handling get_tx({txhash})");
+        let (height, index) = self.storage.get_tx_location(txhash).await?;
+        let ctree = self.get_coin_tree(height).await.ok()?;
+        let (raw_tx, proof) = ctree.get_with_proof(txhash.stdcode().hash().0);
+        if raw_tx.is_empty() {
+            log::warn!("transaction {txhash} is indexed at {height} but not in its block");
+            return None;
+        }
+        let transaction: Transaction = stdcode::deserialize(&raw_tx).ok()?;
+        Some(TxLookup {
+            transaction,
+            height,
+            index,
+            proof: proof.compress(),
+        })
+    }
+
+    async fn get_tx_status(&self, txhash: TxHash) -> TxStatus {
+        log::trace!("handling get_tx_status({txhash})");
+        // check the mempool first: transactions leave it only after being indexed
+        if self.storage.mempool().is_pending(txhash) {
+            return TxStatus::Pending;
+        }
+        if let Some((height, _)) = self.storage.get_tx_location(txhash).await {
+            return TxStatus::Confirmed(height);
+        }
+        match self.storage.mempool().dropped_reason(txhash) {
+            Some(reason) => TxStatus::Dropped(reason),
+            None => TxStatus::Unknown,
+        }
+    }
+
+    async fn get_pending_txx(&self, address: Address) -> Vec<Transaction> {
+        log::trace!("handling get_pending_txx({address})");
+        self.storage.mempool().pending_touching(address)
+    }
+
+    async fn wait_new_block(&self, after: BlockHeight) -> Option<Header> {
+        log::trace!("handling wait_new_block({after})");
+        let height = self
+            .storage
+            .wait_height_above(after)
+            .timeout(LONG_POLL_TIMEOUT)
+            .await?;
+        Some(self.storage.get_block(height).await?.header)
+    }
+
+    async fn wait_headers(
+        &self,
+        after: BlockHeight,
+        limit: usize,
+    ) -> Result<Vec<Header>, StateError> {
+        log::trace!("handling wait_headers({after}, {limit})");
+        let highest = match self
+            .storage
+            .wait_height_above(after)
+            .timeout(LONG_POLL_TIMEOUT)
+            .await
+        {
+            Some(highest) => highest,
+            None => return Ok(vec![]),
+        };
+        let limit = limit.clamp(1, MAX_HEADERS) as u64;
+        let mut headers = vec![];
+        for height in (after.0 + 1)..=highest.0.min(after.0 + limit) {
+            // abbreviated blocks are kept even where full blocks were pruned
+            match self.storage.get_abbr_block(BlockHeight(height)).await {
+                Some((abbr_block, _)) => headers.push(abbr_block.header),
+                None => break,
+            }
+        }
+        // answering with nothing right away would just make the client call again right away
+        if headers.is_empty() {
+            return Err(StateError::Missing(after + BlockHeight(1)));
+        }
+        Ok(headers)
+    }
+
+    async fn get_address_coin_changes(
+        &self,
+        addresses: Vec<Address>,
+        start: BlockHeight,
+        end: BlockHeight,
+        limit: usize,
+    ) -> Result<CoinChangePage, CoinQueryError> {
+        log::debug!(
+            "get_address_coin_changes({} addresses, {start}..={end}, {limit})",
+            addresses.len()
+        );
+        let addresses: BTreeSet<Address> = addresses.into_iter().collect();
+        if addresses.len() > MAX_ADDRESSES {
+            return Err(CoinQueryError::TooManyAddresses(MAX_ADDRESSES));
+        }
+        let indexer = self.get_indexer().await?;
+        let end = end.min(self.storage.highest_height().await);
+        if start > end {
+            return Ok(CoinChangePage {
+                changes: vec![],
+                next: None,
+            });
+        }
+        // only look through a window of heights, so that the work per call is bounded however long the range is
+        let window_end = end.min(start + BlockHeight(MAX_COIN_CHANGE_HEIGHTS - 1));
+        let mut next = if window_end < end {
+            Some(window_end + BlockHeight(1))
+        } else {
+            None
+        };
+
+        let mut changes: Vec<AddressCoinChange> = addresses
+            .into_iter()
+            .flat_map(|address| {
+                coin_changes_in(indexer, address, start.0..=window_end.0)
+                    .into_iter()
+                    .map(move |(height, change)| AddressCoinChange {
+                        height,
+                        address,
+                        change,
+                    })
+            })
+            .collect();
+        changes.sort_by_key(|c| c.height);
+
+        // cut the page at the first height boundary after `limit` changes
+        let limit = limit.clamp(1, MAX_COIN_CHANGES);
+        if changes.len() > limit {
+            let last_height = changes[limit - 1].height;
+            if let Some(cut) = changes.iter().position(|c| c.height > last_height) {
+                next = Some(changes[cut].height);
+                changes.truncate(cut);
+            }
+        }
+        Ok(CoinChangePage { changes, next })
+    }
+
+    async fn get_balance(
+        &self,
+        address: Address,
+        height: BlockHeight,
+    ) -> Result<Vec<Balance>, CoinQueryError> {
+        log::debug!("get_balance({address}, {height})");
+        if height > self.storage.highest_height().await {
+            return Err(StateError::Missing(height).into());
+        }
+        let indexer = self.get_indexer().await?;
+        let mut balances: BTreeMap<Denom, Balance> = BTreeMap::new();
+        for coin in indexer
+            .query_coins()
+            .covhash(address)
+            .create_height_range(0..=height.0)
+            .unspent_by(height)
+            .iter()
+        {
+            let balance = balances
+                .entry(coin.coin_data.denom)
+                .or_insert_with(|| Balance {
+                    denom: coin.coin_data.denom,
+                    total: CoinValue(0),
+                    coin_count: 0,
+                });
+            balance.total += coin.coin_data.value;
+            balance.coin_count += 1;
+        }
+        Ok(balances.into_values().collect())
+    }
+
+    async fn get_proven_coins(
+        &self,
+        address: Address,
+        height: BlockHeight,
+        after: Option<CoinID>,
+    ) -> Result<ProvenCoinPage, CoinQueryError> {
+        log::debug!("get_proven_coins({address}, {height}, {after:?})");
+        let indexer = self.get_indexer().await?;
+        let mut coin_ids: Vec<CoinID> = indexer
+            .query_coins()
+            .covhash(address)
+            .create_height_range(0..=height.0)
+            .unspent_by(height)
+            .iter()
+            .map(|coin| CoinID::new(coin.create_txhash, coin.create_index))
+            .filter(|coin_id| after.map(|after| *coin_id > after).unwrap_or(true))
+            .collect();
+        coin_ids.sort_unstable();
+        let next = if coin_ids.len() > MAX_PROVEN_COINS {
+            coin_ids.truncate(MAX_PROVEN_COINS);
+            coin_ids.last().copied()
+        } else {
+            None
+        };
+        let state = self.storage.get_state(height).await?;
+        let coins_smt = state.raw_coins_smt();
+        let mut coins = vec![];
+        for coin_id in coin_ids {
+            // the value in the SMT, not the indexer, is what the proof is for
+            let (raw, proof) = coins_smt.get_with_proof(coin_id.stdcode().hash().0);
+            let coin_data = match stdcode::deserialize(&raw) {
+                Ok(coin_data) => coin_data,
+                Err(_) => {
+                    log::warn!(
+                        "coin {coin_id} is indexed as unspent at {height} but not in its state"
+                    );
+                    continue;
+                }
+            };
+            coins.push(ProvenCoin {
+                coin_id,
+                coin_data,
+                proof: proof.compress(),
+            });
+        }
+        Ok(ProvenCoinPage { coins, next })
+    }
+
+    async fn get_smt_branches(
+        &self,
+        height: BlockHeight,
+        keys: Vec<(Substate, HashVal)>,
+    ) -> Option<Vec<(Vec<u8>, CompressedProof)>> {
+        log::debug!("get_smt_branches({height}, {} keys)", keys.len());
+        if keys.len() > MAX_SMT_KEYS {
+            return None;
+        }
+        let state = self.storage.get_state(height).await.ok()?;
+        // the stakes SMT is rebuilt from scratch and the transactions SMT loaded from storage, so do either only if asked for, and at most once
+        let stakes_smt = if keys
+            .iter()
+            .any(|(elem, _)| matches!(elem, Substate::Stakes))
+        {
+            Some(state.raw_stakes().pre_tip911())
+        } else {
+            None
+        };
+        let ctree = if keys
+            .iter()
+            .any(|(elem, _)| matches!(elem, Substate::Transactions))
+        {
+            Some(self.get_coin_tree(height).await.ok()?)
+        } else {
+            None
+        };
+        keys.into_iter()
+            .map(|(elem, key)| smt_branch(&state, stakes_smt.as_ref(), ctree.as_ref(), elem, key))
+            .collect()
+    }
+
+    async fn get_active_stakers(&self, height: BlockHeight) -> Option<Vec<ProvenStake>> {
+        log::debug!("get_active_stakers({height})");
+        let state = self.storage.get_state(height).await.ok()?;
+        let weights = vote_weights(&state).ok()?;
+        let stakes = state.raw_stakes();
+        let stakes_smt = stakes.pre_tip911();
+        let epoch = (height + BlockHeight(1)).epoch();
+        let stakers = stakes
+            .iter()
+            .filter_map(|(txhash, stake_doc)| {
+                let weight = *weights.get(&stake_doc.pubkey)?;
+                if epoch < stake_doc.e_start || epoch >= stake_doc.e_post_end {
+                    return None;
+                }
+                let (_, proof) = stakes_smt.get_with_proof(txhash.0.hash().0);
+                Some(ProvenStake {
+                    txhash: *txhash,
+                    stake_doc: stake_doc.clone(),
+                    weight,
+                    proof: proof.compress(),
+                })
+            })
+            .collect();
+        Some(stakers)
+    }
+
+    async fn get_snapshot_meta(&self, height: BlockHeight) -> Option<SnapshotMeta> {
+        log::debug!("get_snapshot_meta({height})");
+        self.storage.get_snapshot_meta(height).await
+    }
+
+    async fn get_lz4_smt_nodes(&self, hashes: Vec<HashVal>) -> Option<String> {
+        log::debug!("get_lz4_smt_nodes({} hashes)", hashes.len());
+        if hashes.len() > MAX_SMT_NODES {
+            return None;
+        }
+        let cas = self.storage.forest().storage();
+        let nodes: Vec<Vec<u8>> = hashes
+            .iter()
+            .map(|hash| cas.get(&hash.0).map(|raw| raw.to_vec()).unwrap_or_default())
+            .collect();
+        let compressed = lz4_flex::compress_prepend_size(&nodes.stdcode());
+        Some(base64::engine::general_purpose::STANDARD_NO_PAD.encode(compressed))
+    }
+
+    async fn get_lowest_height(&self) -> BlockHeight {
+        log::trace!("get_lowest_height()");
+        self.storage.lowest_height().await
+    }
+}
+
+/// Gets the coin changes of an address within a range of heights, each with the height it happened at. Within each height, additions come before deletions.
+fn coin_changes_in(
+    indexer: &Indexer,
+    covhash: Address,
+    heights: RangeInclusive<u64>,
+) -> Vec<(BlockHeight, CoinChange)> {
+    let added_coins: Vec<CoinInfo> = indexer
+        .query_coins()
+        .covhash(covhash)
+        .create_height_range(heights.clone())
+        .iter()
+        .collect();
+    let deleted_coins: Vec<CoinInfo> = indexer
+        .query_coins()
+        .covhash(covhash)
+        .spend_height_range(heights)
+        .iter()
+        .collect();
+
+    let added = added_coins.iter().map(|coin| {
+        (
+            coin.create_height,
+            CoinChange::Add(CoinID::new(coin.create_txhash, coin.create_index)),
+        )
+    });
+    let deleted = deleted_coins.iter().filter_map(|coin| {
+        let spend_info = coin.spend_info?;
+        Some((
+            spend_info.spend_height,
+            CoinChange::Delete(
+                CoinID::new(coin.create_txhash, coin.create_index),
+                spend_info.spend_txhash,
+            ),
+        ))
+    });
+    let mut changes: Vec<(BlockHeight, CoinChange)> = added.chain(deleted).collect();
+    changes.sort_by_key(|(height, _)| *height);
+    changes
+}
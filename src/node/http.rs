@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+
+use melprot::NodeRpcProtocol;
+use melstructs::{Address, BlockHeight, TxHash};
+use serde::Serialize;
+
+use crate::http_server::{start_http_server, HttpResponse};
+
+use super::{NodeExtRpcProtocol, NodeRpcImpl};
+
+/// Starts the HTTP/JSON gateway on the given address. It answers read-only `GET` queries with the same [NodeRpcImpl] that backs the node protocol, for dashboards and scripts that would rather not speak nanorpc:
+///
+/// - `/summary`
+/// - `/block/{height}`
+/// - `/tx/{txhash}`
+/// - `/coins/{address}`
+/// - `/balance/{address}`
+/// - `/coin_changes/{height}/{address}`
+///
+/// When listening on a loopback address, it also serves `/peers`, reporting how every peer this node has synced from or broadcast to has behaved, best first, including which peers are currently banned.
+pub async fn start_http_gateway(
+    rpc: NodeRpcImpl,
+    listen_addr: SocketAddr,
+) -> anyhow::Result<smol::Task<()>> {
+    // only the node's operator should see how it rates its peers
+    let admin = listen_addr.ip().is_loopback();
+    let task = start_http_server(listen_addr, move |request_line| {
+        let rpc = rpc.clone();
+        async move { Ok(gateway_response(&rpc, &request_line, admin).await) }
+    })
+    .await?;
+    log::info!("serving the HTTP gateway at http://{listen_addr}/");
+    Ok(task)
+}
+
+/// Responds to a single HTTP request with JSON.
+async fn gateway_response(rpc: &NodeRpcImpl, request_line: &str, admin: bool) -> HttpResponse {
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    log::trace!("HTTP gateway: {method} {path}");
+    let (status, body) = if method != "GET" {
+        (
+            "405 Method Not Allowed",
+            error_json("only GET is supported"),
+        )
+    } else {
+        match route(rpc, path, admin).await {
+            Ok(Some(body)) => ("200 OK", body),
+            Ok(None) => ("404 Not Found", error_json("not found")),
+            Err(err) => ("400 Bad Request", error_json(&err.to_string())),
+        }
+    };
+    HttpResponse {
+        status,
+        headers: vec![
+            ("Content-Type", "application/json".into()),
+            ("Access-Control-Allow-Origin", "*".into()),
+        ],
+        body,
+    }
+}
+
+/// Answers a request path, returning `None` if there's nothing there and an error if the path is malformed. Admin endpoints are only there if `admin` is set.
+async fn route(rpc: &NodeRpcImpl, path: &str, admin: bool) -> anyhow::Result<Option<Vec<u8>>> {
+    let path = path.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["summary"] => to_json(Some(rpc.get_summary().await)),
+        ["block", height] => {
+            let height: BlockHeight = height.parse()?;
+            to_json(rpc.get_block(height).await)
+        }
+        ["tx", txhash] => {
+            let txhash: TxHash = txhash.parse()?;
+            to_json(rpc.get_tx(txhash).await)
+        }
+        ["coins", address] => {
+            let address: Address = address.parse()?;
+            let height = rpc.storage.highest_height().await;
+            to_json(rpc.get_some_coins(height, address).await)
+        }
+        ["balance", address] => {
+            let address: Address = address.parse()?;
+            let height = rpc.storage.highest_height().await;
+            to_json(Some(rpc.get_balance(address, height).await?))
+        }
+        ["coin_changes", height, address] => {
+            let height: BlockHeight = height.parse()?;
+            let address: Address = address.parse()?;
+            to_json(rpc.get_coin_changes(height, address).await)
+        }
+        ["peers"] if admin => to_json(Some(rpc.reputation.table())),
+        _ => Ok(None),
+    }
+}
+
+/// Serializes an RPC response, where `None` means not found.
+fn to_json(val: Option<impl Serialize>) -> anyhow::Result<Option<Vec<u8>>> {
+    Ok(match val {
+        Some(val) => Some(serde_json::to_vec(&val)?),
+        None => None,
+    })
+}
+
+/// The body of an error response.
+fn error_json(msg: &str) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({ "error": msg })).unwrap()
+}
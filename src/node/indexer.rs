@@ -1,13 +1,53 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use melblkidx::Indexer;
 use melprot::Client;
 
-use melstructs::{Checkpoint, NetID};
+use melstructs::{BlockHeight, Checkpoint, NetID};
+use serde::{Deserialize, Serialize};
 
 use crate::storage::Storage;
 
+/// Why the coin indexer cannot answer a query.
+#[derive(thiserror::Error, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum IndexerError {
+    #[error("this node does not index coins")]
+    Disabled,
+    #[error("the coin indexer is at {indexed}, behind the highest block {wanted}")]
+    Lagging {
+        indexed: BlockHeight,
+        wanted: BlockHeight,
+    },
+}
+
+/// Waits until the indexer, whose height is polled with `indexed`, reaches the `wanted` height. Gives up once `timeout` has passed, rather than holding up the caller forever.
+pub async fn wait_for_height(
+    indexed: impl Fn() -> BlockHeight,
+    wanted: BlockHeight,
+    timeout: Duration,
+) -> Result<(), IndexerError> {
+    let start = Instant::now();
+    while indexed() < wanted {
+        if start.elapsed() > timeout {
+            log::warn!(
+                "indexer is stuck at {} while the highest block is {wanted}",
+                indexed()
+            );
+            return Err(IndexerError::Lagging {
+                indexed: indexed(),
+                wanted,
+            });
+        }
+        log::debug!("waiting for {wanted} to be available at the indexer...");
+        smol::Timer::after(Duration::from_millis(200)).await;
+    }
+    Ok(())
+}
+
 pub struct WrappedIndexer {
     indexer: Indexer,
     _task: smol::Task<()>,
@@ -40,11 +80,68 @@ impl WrappedIndexer {
 
 async fn indexer_loop(storage: Storage, client: Client) {
     loop {
-        let trusted_height = storage.highest_state().await;
+        let trusted_header = storage.highest_header().await;
         client.trust(Checkpoint {
-            height: trusted_height.header().height,
-            header_hash: trusted_height.header().hash(),
+            height: trusted_header.height,
+            header_hash: trusted_header.hash(),
         });
         smol::Timer::after(Duration::from_secs(1)).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicU64, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use melstructs::BlockHeight;
+
+    use super::{wait_for_height, IndexerError};
+
+    #[test]
+    fn lagging_indexer_times_out() {
+        let start = Instant::now();
+        let result = smol::block_on(wait_for_height(
+            || BlockHeight(90),
+            BlockHeight(100),
+            Duration::from_millis(500),
+        ));
+        assert!(matches!(
+            result,
+            Err(IndexerError::Lagging {
+                indexed: BlockHeight(90),
+                wanted: BlockHeight(100),
+            })
+        ));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(500));
+        assert!(elapsed < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn indexer_catching_up_in_time() {
+        // every poll indexes another block
+        let indexed = AtomicU64::new(95);
+        let result = smol::block_on(wait_for_height(
+            || BlockHeight(indexed.fetch_add(1, Ordering::SeqCst)),
+            BlockHeight(100),
+            Duration::from_secs(10),
+        ));
+        assert!(result.is_ok());
+        assert!(indexed.load(Ordering::SeqCst) > 100);
+    }
+
+    #[test]
+    fn indexer_ahead_does_not_wait() {
+        let start = Instant::now();
+        let result = smol::block_on(wait_for_height(
+            || BlockHeight(101),
+            BlockHeight(100),
+            Duration::ZERO,
+        ));
+        assert!(result.is_ok());
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+}
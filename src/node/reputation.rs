@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// How long a peer that sent invalid data is banned for.
+const INVALID_BAN: Duration = Duration::from_secs(600);
+
+/// How long a peer that timed out too many times in a row is banned for.
+const TIMEOUT_BAN: Duration = Duration::from_secs(60);
+
+/// How many timeouts in a row get a peer banned.
+const MAX_CONSECUTIVE_TIMEOUTS: u64 = 3;
+
+/// What happened when talking to a peer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerOutcome {
+    /// The peer answered with valid data.
+    Success,
+    /// The peer was unreachable, or too slow to answer.
+    Timeout,
+    /// The peer answered with invalid data.
+    Invalid,
+}
+
+/// How a peer has behaved so far.
+#[derive(Default)]
+struct PeerRecord {
+    successes: u64,
+    timeouts: u64,
+    invalid: u64,
+    consecutive_timeouts: u64,
+    banned_until: Option<Instant>,
+}
+
+impl PeerRecord {
+    /// Higher is better. Invalid data weighs much more than timeouts, which can happen to anyone.
+    fn score(&self) -> i64 {
+        self.successes as i64 - 2 * self.timeouts as i64 - 20 * self.invalid as i64
+    }
+
+    fn banned(&self) -> bool {
+        self.banned_until
+            .map(|until| until > Instant::now())
+            .unwrap_or_default()
+    }
+}
+
+/// A peer's reputation, as reported by the `/peers` endpoint of the HTTP gateway.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerReputation {
+    pub addr: String,
+    pub successes: u64,
+    pub timeouts: u64,
+    pub invalid: u64,
+    pub score: i64,
+    /// Seconds until the peer is no longer banned, if it is.
+    pub banned_secs: Option<u64>,
+}
+
+/// Tracks how well each peer in the swarm has behaved, so that misbehaving peers can be deprioritized, or banned for a while.
+#[derive(Clone, Default)]
+pub struct PeerTable {
+    records: Arc<Mutex<HashMap<String, PeerRecord>>>,
+}
+
+impl PeerTable {
+    /// Records what happened when talking to a peer.
+    pub fn record(&self, addr: impl Display, outcome: PeerOutcome) {
+        let addr = addr.to_string();
+        let mut records = self.records.lock();
+        let record = records.entry(addr.clone()).or_default();
+        match outcome {
+            PeerOutcome::Success => {
+                record.successes += 1;
+                record.consecutive_timeouts = 0;
+            }
+            PeerOutcome::Timeout => {
+                record.timeouts += 1;
+                record.consecutive_timeouts += 1;
+                if record.consecutive_timeouts >= MAX_CONSECUTIVE_TIMEOUTS {
+                    log::warn!(
+                        "banning {addr} for {:?} after repeated timeouts",
+                        TIMEOUT_BAN
+                    );
+                    record.banned_until = Some(Instant::now() + TIMEOUT_BAN);
+                    record.consecutive_timeouts = 0;
+                }
+            }
+            PeerOutcome::Invalid => {
+                record.invalid += 1;
+                log::warn!("banning {addr} for {:?} after invalid data", INVALID_BAN);
+                record.banned_until = Some(Instant::now() + INVALID_BAN);
+            }
+        }
+    }
+
+    /// Drops the banned peers from a list, and orders the rest best first. Peers with equal scores keep their relative order.
+    pub fn rank<A: Display>(&self, mut addrs: Vec<A>) -> Vec<A> {
+        let records = self.records.lock();
+        let score = |addr: &A| {
+            records
+                .get(&addr.to_string())
+                .map(|record| (record.banned(), record.score()))
+                .unwrap_or_default()
+        };
+        addrs.retain(|addr| !score(addr).0);
+        addrs.sort_by_key(|addr| std::cmp::Reverse(score(addr).1));
+        addrs
+    }
+
+    /// Reports the reputation of every peer we've talked to, best first.
+    pub fn table(&self) -> Vec<PeerReputation> {
+        let now = Instant::now();
+        let mut table: Vec<PeerReputation> = self
+            .records
+            .lock()
+            .iter()
+            .map(|(addr, record)| PeerReputation {
+                addr: addr.clone(),
+                successes: record.successes,
+                timeouts: record.timeouts,
+                invalid: record.invalid,
+                score: record.score(),
+                banned_secs: record
+                    .banned_until
+                    .filter(|until| *until > now)
+                    .map(|until| (until - now).as_secs()),
+            })
+            .collect();
+        table.sort_by_key(|rep| std::cmp::Reverse(rep.score));
+        table
+    }
+}
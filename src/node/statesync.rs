@@ -0,0 +1,73 @@
+use crate::storage::Storage;
+use anyhow::Context;
+use base64::Engine;
+use melnet2::{wire::http::HttpBackhaul, Swarm};
+use melprot::NodeRpcClient;
+use melstructs::Checkpoint;
+use smol_timeout::TimeoutExt;
+use std::time::Duration;
+use tmelcrypt::HashVal;
+
+use super::NodeExtRpcClient;
+
+/// If the storage is empty, seeds it with the state at the trusted checkpoint, trying peers until one of them provides it. Block sync can then continue from the checkpoint instead of from genesis.
+pub async fn statesync_loop(
+    swarm: &Swarm<HttpBackhaul, NodeRpcClient>,
+    storage: &Storage,
+    checkpoint: &Checkpoint,
+) {
+    let highest = storage.highest_height().await;
+    if highest.0 > 0 {
+        log::info!("already at height {highest}, not syncing the state at the trusted checkpoint");
+        return;
+    }
+    loop {
+        let routes = swarm.routes().await;
+        if !routes.is_empty() {
+            let peer = routes[fastrand::usize(..routes.len())].clone();
+            log::info!(
+                "syncing the state at trusted checkpoint {} from {peer}",
+                checkpoint.height
+            );
+            let fallible_part = async {
+                let client = NodeExtRpcClient(swarm.connect(peer.clone()).await?.0);
+                attempt_statesync(&client, storage, checkpoint).await
+            };
+            match fallible_part.await {
+                Ok(()) => return,
+                Err(e) => log::warn!("failed to statesync with {}: {:?}", peer, e),
+            }
+        }
+        smol::Timer::after(Duration::from_secs(1)).await;
+    }
+}
+
+/// Attempts to seed the storage with the state at the trusted checkpoint, using the given node client.
+async fn attempt_statesync(
+    client: &NodeExtRpcClient,
+    storage: &Storage,
+    checkpoint: &Checkpoint,
+) -> anyhow::Result<()> {
+    let meta = client
+        .get_snapshot_meta(checkpoint.height)
+        .timeout(Duration::from_secs(30))
+        .await
+        .context("timed out getting the checkpoint block")?
+        .context("cannot get the checkpoint block")?
+        .context("peer does not have the checkpoint block")?;
+    storage
+        .import_state(checkpoint.clone(), meta, |hashes| async move {
+            let compressed = client
+                .get_lz4_smt_nodes(hashes.into_iter().map(HashVal).collect())
+                .timeout(Duration::from_secs(30))
+                .await
+                .context("timeout while getting SMT nodes")?
+                .context("failed to get SMT nodes")?
+                .context("peer refused to give SMT nodes")?;
+            let compressed =
+                base64::engine::general_purpose::STANDARD_NO_PAD.decode(compressed.as_bytes())?;
+            let decompressed = lz4_flex::decompress_size_prepended(&compressed)?;
+            Ok(stdcode::deserialize::<Vec<Vec<u8>>>(&decompressed)?)
+        })
+        .await
+}
@@ -1,6 +1,6 @@
 use crate::{
     args::StakerConfig,
-    storage::{MeshaCas, Storage},
+    storage::{vote_weights, MeshaCas, Storage, VoteTally},
 };
 
 use anyhow::Context;
@@ -13,7 +13,7 @@ use melnet2::{wire::http::HttpBackhaul, Swarm};
 use nanorpc::{nanorpc_derive, DynRpcTransport};
 
 use melstf::SealedState;
-use melstructs::{Block, BlockHeight, ConsensusProof, NetID, ProposerAction, StakeDoc};
+use melstructs::{Block, BlockHeight, ConsensusProof, NetID, ProposerAction};
 use once_cell::sync::Lazy;
 use parking_lot::RwLock;
 use smol::{
@@ -69,11 +69,26 @@ type DiffReq = (
     async_oneshot::Sender<Vec<DiffMessage>>,
 );
 
+/// How long the staker waits before restarting after a failure, which doubles with every failure in a row up to [MAX_RESTART_BACKOFF].
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The longest the staker waits before restarting after a failure.
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+
 async fn network_task(storage: Storage, cfg: StakerConfig) {
+    let mut backoff = MIN_RESTART_BACKOFF;
     loop {
-        if let Err(err) = network_task_inner(storage.clone(), cfg.clone()).await {
-            log::warn!("staker failed: {:?}", err);
+        let start = Instant::now();
+        let result = network_task_inner(storage.clone(), cfg.clone()).await;
+        // a staker that ran for a while before failing isn't failing in a row
+        if start.elapsed() > MAX_RESTART_BACKOFF {
+            backoff = MIN_RESTART_BACKOFF;
+        }
+        if let Err(err) = result {
+            log::warn!("staker failed, restarting in {:?}: {:?}", backoff, err);
         }
+        smol::Timer::after(backoff).await;
+        backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
     }
 }
 
@@ -109,7 +124,7 @@ async fn network_task_inner(storage: Storage, cfg: StakerConfig) -> anyhow::Resu
         .context("cannot start listen")?;
     // TODO better time calcs
     loop {
-        let base_state = storage.highest_state().await;
+        let base_state = storage.highest_state().await?;
         let next_height: BlockHeight = base_state.header().height + BlockHeight(1);
         let skip_round = async {
             storage.get_state_or_wait(next_height).await;
@@ -190,13 +205,16 @@ async fn network_task_inner(storage: Storage, cfg: StakerConfig) -> anyhow::Resu
                 let _spammer = smolscale::spawn(async move { decider.sync_state(None).await });
 
                 // then, until we finally have enough signatures, we spam our neighbors incessantly.
-                let stakes = base_state.raw_stakes();
-                let epoch = base_state.header().height.epoch();
-                let vote_threshold = stakes.total_votes(epoch) * 2 / 3;
+                let weights = vote_weights(&base_state)?;
+                let header_hash = decision.header.hash();
                 let get_proof = || {
                     let map = sig_gather.entry(decision.header.height).or_default();
-                    if map.keys().map(|pk| stakes.votes(epoch, *pk)).sum::<u128>() > vote_threshold
-                    {
+                    // signatures from neighbors aren't checked as they come in, so only count the valid ones
+                    let signers = map
+                        .iter()
+                        .filter(|(pk, sig)| pk.verify(&header_hash, sig))
+                        .map(|(pk, _)| pk);
+                    if VoteTally::new(&weights, signers).has_quorum() {
                         Some(map)
                     } else {
                         None
@@ -213,6 +231,9 @@ async fn network_task_inner(storage: Storage, cfg: StakerConfig) -> anyhow::Resu
                             "{log_key} COMMITTED the newly decided block within {:?}",
                             consensus_start_time.elapsed()
                         );
+                        #[cfg(feature = "metrics")]
+                        crate::metrics::CONSENSUS_ROUND_SECONDS
+                            .observe(consensus_start_time.elapsed().as_secs_f64());
                         break;
                     }
                     let random_neigh = swarm.routes().await.first().cloned();
@@ -335,18 +356,11 @@ impl DeciderConfig for StakerInner {
     }
 
     fn vote_weights(&self) -> BTreeMap<tmelcrypt::Ed25519PK, u64> {
-        let height: BlockHeight = self.base_state.header().height + BlockHeight(1);
-        self.base_state
-            .raw_stakes()
-            .pre_tip911()
-            .iter()
-            .fold(BTreeMap::new(), |mut map, val| {
-                let stake_doc: StakeDoc = stdcode::deserialize(&val.1).unwrap();
-                if height.epoch() >= stake_doc.e_start && height.epoch() < stake_doc.e_post_end {
-                    *map.entry(stake_doc.pubkey).or_default() += stake_doc.syms_staked.0 as u64;
-                }
-                map
-            })
+        vote_weights(&self.base_state)
+            .expect("corrupt stake doc in the base state")
+            .into_iter()
+            .map(|(pk, weight)| (pk, weight as u64))
+            .collect()
     }
 
     fn seed(&self) -> u128 {
@@ -0,0 +1,129 @@
+use std::collections::HashSet;
+
+use novasmt::{hash_data, hash_node, ContentAddrStore, Hashed};
+
+use super::MeshaCas;
+
+/// What compacting the SMT forest did.
+#[derive(Clone, Copy, Debug)]
+pub struct CompactionStats {
+    /// Number of reachable SMT nodes kept.
+    pub nodes_kept: u64,
+    /// Size of the database before compaction.
+    pub bytes_before: u64,
+    /// Size of the database after compaction.
+    pub bytes_after: u64,
+}
+
+impl CompactionStats {
+    /// How many bytes compaction freed up.
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_before.saturating_sub(self.bytes_after)
+    }
+}
+
+/// Copies every SMT node reachable from the given roots from one store into another, returning how many nodes were copied. Anything left behind is garbage.
+pub fn copy_reachable(
+    old: &MeshaCas,
+    new: &MeshaCas,
+    roots: impl IntoIterator<Item = Hashed>,
+) -> anyhow::Result<u64> {
+    // the new store doubles as the set of nodes already visited, since keeping that in memory could take tens of GB for a full history
+    walk(
+        old,
+        roots,
+        |node| new.get(&node).is_some(),
+        |node, raw| {
+            new.insert(&node, raw);
+            Ok(())
+        },
+    )
+}
+
+/// Calls `visit` on every SMT node reachable from the given roots, exactly once each, returning how many nodes were visited. Fails if any reachable node is missing from the store.
+pub fn walk_reachable(
+    store: &MeshaCas,
+    roots: impl IntoIterator<Item = Hashed>,
+    visit: impl FnMut(Hashed, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<u64> {
+    let mut visited: HashSet<Hashed> = HashSet::new();
+    walk(store, roots, |node| !visited.insert(node), visit)
+}
+
+/// Calls `visit` on every SMT node reachable from the given roots that `seen` doesn't report as already visited, returning how many nodes were visited. `seen` must report every node `visit` was called on as visited from then on.
+fn walk(
+    store: &MeshaCas,
+    roots: impl IntoIterator<Item = Hashed>,
+    mut seen: impl FnMut(Hashed) -> bool,
+    mut visit: impl FnMut(Hashed, &[u8]) -> anyhow::Result<()>,
+) -> anyhow::Result<u64> {
+    let mut count = 0u64;
+    let mut stack: Vec<Hashed> = roots.into_iter().collect();
+    while let Some(node) = stack.pop() {
+        // identical hashes mean identical subtrees, so states sharing structure are only walked once
+        if node == [0; 32] || seen(node) {
+            continue;
+        }
+        let raw = store
+            .get(&node)
+            .ok_or_else(|| anyhow::anyhow!("dangling SMT node {}", hex::encode(node)))?;
+        stack.extend(node_children(&raw));
+        visit(node, &raw)?;
+        count += 1;
+        if count % 1_000_000 == 0 {
+            log::debug!("walked {count} reachable SMT nodes so far");
+        }
+    }
+    Ok(count)
+}
+
+/// Decodes the children of a raw novasmt node. Leaves start with a zero byte and have no children; internal nodes are a height byte and an 8-byte count, followed by 16 child hashes.
+pub fn node_children(raw: &[u8]) -> Vec<Hashed> {
+    if raw.len() != 1 + 8 + 32 * 16 || raw[0] == 0 {
+        return vec![];
+    }
+    raw[9..]
+        .chunks_exact(32)
+        .map(|chunk| chunk.try_into().unwrap())
+        .collect()
+}
+
+/// Computes the hash of a raw novasmt node the same way novasmt does, returning `None` if the node is malformed. Nodes from untrusted sources must hash to the key they are stored under before novasmt gets to see them, since it panics on corrupt nodes.
+pub fn node_hash(raw: &[u8]) -> Option<Hashed> {
+    match *raw.first()? {
+        0 => {
+            if raw.len() < 1 + 1 + 32 || raw[1] > 64 {
+                return None;
+            }
+            let key: Hashed = raw[2..34].try_into().unwrap();
+            Some(singleton_root(raw[1] as usize * 4, key, &raw[34..]))
+        }
+        height => {
+            if raw.len() != 1 + 8 + 32 * 16 || height > 64 {
+                return None;
+            }
+            let mut level = node_children(raw);
+            while level.len() > 1 {
+                level = level
+                    .chunks_exact(2)
+                    .map(|pair| hash_node(pair[0], pair[1]))
+                    .collect();
+            }
+            Some(level[0])
+        }
+    }
+}
+
+/// The root of a binary SMT of the given height holding just one value, hashed from the leaf up.
+fn singleton_root(height: usize, key: Hashed, value: &[u8]) -> Hashed {
+    let mut hash = hash_data(value);
+    for bit in 0..height {
+        // going up the tree means going from the least to the most significant bit of the key
+        hash = if (key[31 - bit / 8] >> (bit % 8)) & 1 == 1 {
+            hash_node([0; 32], hash)
+        } else {
+            hash_node(hash, [0; 32])
+        };
+    }
+    hash
+}
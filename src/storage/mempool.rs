@@ -1,19 +1,131 @@
 use crate::storage::MeshaCas;
 
-use std::collections::HashSet;
+use std::{
+    cmp::Ordering,
+    collections::HashSet,
+    time::{Duration, Instant},
+};
 
+use lru::LruCache;
 use melstf::{SealedState, StateError, UnsealedState};
-use melstructs::{Transaction, TxHash};
+use melstructs::{Address, Transaction, TxHash};
 use melvm::covenant_weight_from_bytes;
 
 const WEIGHT_LIMIT: u128 = 10_000_000;
 
+/// How many recently seen transactions are kept around for [Mempool::lookup_recent_tx].
+const RECENT_TX_LIMIT: usize = 10_000;
+
+/// How long a recently seen transaction is kept around for [Mempool::lookup_recent_tx].
+const RECENT_TX_WINDOW: Duration = Duration::from_secs(600);
+
+/// A transaction waiting in the mempool, along with its precomputed hash and weight.
+#[derive(Clone)]
+struct PendingTx {
+    tx: Transaction,
+    txhash: TxHash,
+    weight: u128,
+}
+
+impl PendingTx {
+    fn new(tx: Transaction) -> Self {
+        Self {
+            txhash: tx.hash_nosigs(),
+            weight: tx.weight(covenant_weight_from_bytes),
+            tx,
+        }
+    }
+
+    /// Compares fee-per-weight with another transaction, without any division.
+    fn cmp_priority(&self, other: &Self) -> Ordering {
+        let ours = self.tx.fee.0.saturating_mul(other.weight.max(1));
+        let theirs = other.tx.fee.0.saturating_mul(self.weight.max(1));
+        ours.cmp(&theirs)
+    }
+}
+
+/// Finds the lowest-paying pending transactions, together with their dependents, that must be evicted to free up at least `needed` weight for `new`. A transaction is only evicted along with its dependents if all of them pay less per unit of weight than `new`, so that a low-paying parent can't drag better-paying children out with it. Returns `None` if not enough weight can be freed that way, or if `new` spends from something that would be evicted.
+fn eviction_victims(
+    pending: &[PendingTx],
+    new: &PendingTx,
+    needed: u128,
+) -> Option<HashSet<TxHash>> {
+    let mut by_priority: Vec<&PendingTx> = pending.iter().collect();
+    by_priority.sort_by(|a, b| a.cmp_priority(b));
+
+    let mut victims = HashSet::new();
+    let mut freed = 0u128;
+    for candidate in by_priority {
+        if freed >= needed {
+            break;
+        }
+        if candidate.cmp_priority(new) != Ordering::Less {
+            // everything from here on pays at least as well as `new`
+            break;
+        }
+        if victims.contains(&candidate.txhash) {
+            continue;
+        }
+        let package: Vec<&PendingTx> = with_dependents(pending, candidate.txhash)
+            .into_iter()
+            .filter(|p| !victims.contains(&p.txhash))
+            .collect();
+        if package
+            .iter()
+            .any(|p| p.cmp_priority(new) != Ordering::Less)
+        {
+            continue;
+        }
+        for p in package {
+            victims.insert(p.txhash);
+            freed += p.weight;
+        }
+    }
+    if freed < needed || new.tx.inputs.iter().any(|i| victims.contains(&i.txhash)) {
+        return None;
+    }
+    Some(victims)
+}
+
+/// Returns the given pending transaction, plus every pending transaction that transitively spends its outputs.
+fn with_dependents(pending: &[PendingTx], root: TxHash) -> Vec<&PendingTx> {
+    let mut closure = HashSet::new();
+    closure.insert(root);
+    // dependents are always applied after what they depend on, so a single pass suffices
+    pending
+        .iter()
+        .filter(|p| {
+            if p.txhash == root || p.tx.inputs.iter().any(|i| closure.contains(&i.txhash)) {
+                closure.insert(p.txhash);
+                true
+            } else {
+                false
+            }
+        })
+        .collect()
+}
+
+/// A transaction that was dropped from the mempool because it no longer applies.
+#[derive(Clone, Debug)]
+pub struct DroppedTx {
+    pub tx: Transaction,
+    pub reason: String,
+}
+
 /// Mempool encapsulates a "mempool" --- a provisional state that is used to form new blocks by stakers, or provisionally validate transactions by replicas.
+///
+/// When the mempool is full, transactions paying the least fee per unit of weight are evicted, together with every pending transaction spending their outputs, to make room for better-paying ones.
 pub struct Mempool {
     provisional_state: UnsealedState<MeshaCas>,
     last_rebase: UnsealedState<MeshaCas>,
+    /// Pending transactions, in the order they were applied to the provisional state.
+    pending: Vec<PendingTx>,
     txx_in_state: HashSet<TxHash>,
-    next_weight: u128, // seen: LruCache<TxHash, ()>,
+    next_weight: u128,
+    /// Transactions seen recently, whether or not they are still pending, with the time they were last seen.
+    recent: LruCache<TxHash, (Transaction, Instant)>,
+    /// Why recently dropped transactions were dropped.
+    dropped: LruCache<TxHash, String>,
 }
 
 impl Mempool {
@@ -22,9 +134,11 @@ impl Mempool {
         Self {
             provisional_state: state.clone(),
             last_rebase: state,
+            pending: vec![],
             txx_in_state: Default::default(),
             next_weight: 0,
-            // seen: LruCache::new(10000),
+            recent: LruCache::new(RECENT_TX_LIMIT),
+            dropped: LruCache::new(RECENT_TX_LIMIT),
         }
     }
     /// Creates a State based on the present state of the mempool.
@@ -32,43 +146,225 @@ impl Mempool {
         self.provisional_state.clone()
     }
 
-    /// Tries to add a transaction to the mempool.
+    /// Tries to add a transaction to the mempool. If the mempool is full, lower-paying transactions are evicted to make room, or the transaction is rejected if it doesn't pay more than them.
     pub fn apply_transaction(&mut self, tx: &Transaction) -> anyhow::Result<()> {
-        if self.next_weight < WEIGHT_LIMIT {
-            if !self.txx_in_state.insert(tx.hash_nosigs()) {
-                return Err(StateError::DuplicateTx.into());
-            }
+        let new = PendingTx::new(tx.clone());
+        if self.txx_in_state.contains(&new.txhash) {
+            return Err(StateError::DuplicateTx.into());
+        }
+        if new.weight > WEIGHT_LIMIT {
+            anyhow::bail!("transaction is heavier than the whole mempool")
+        }
+
+        let overflow = (self.next_weight + new.weight).saturating_sub(WEIGHT_LIMIT);
+        if overflow == 0 {
             self.provisional_state.apply_tx(tx)?;
-            self.next_weight += tx.weight(covenant_weight_from_bytes);
-            // self.seen.put(tx.hash_nosigs(), ());
-            Ok(())
         } else {
-            anyhow::bail!("mempool is full, try again later")
+            // validate first, so that invalid transactions can't make us evict anything
+            self.provisional_state.clone().apply_tx(tx)?;
+            let victims = eviction_victims(&self.pending, &new, overflow)
+                .ok_or_else(|| anyhow::anyhow!("mempool is full, try again later"))?;
+            log::debug!(
+                "evicting {} mempool txx to make room for {}",
+                victims.len(),
+                new.txhash
+            );
+            self.pending.retain(|p| !victims.contains(&p.txhash));
+            for victim in victims {
+                self.dropped
+                    .put(victim, "evicted by higher-paying transactions".into());
+            }
+            self.rebuild_provisional();
+            self.provisional_state.apply_tx(tx)?;
         }
+
+        self.txx_in_state.insert(new.txhash);
+        self.dropped.pop(&new.txhash);
+        self.next_weight += new.weight;
+        self.remember(new.txhash, new.tx.clone());
+        self.pending.push(new);
+        self.report_metrics();
+        Ok(())
     }
 
-    /// Forcibly replaces the internal state of the mempool with the given state.
-    pub fn rebase(&mut self, state: SealedState<MeshaCas>) {
-        let current_sealed = self.provisional_state.clone().seal(None);
+    /// Rebuilds the provisional state by reapplying the pending transactions on top of the last rebase, dropping and returning any that no longer apply.
+    fn rebuild_provisional(&mut self) -> Vec<DroppedTx> {
+        let mut state = self.last_rebase.clone();
+        let mut dropped = vec![];
+        for p in std::mem::take(&mut self.pending) {
+            match state.apply_tx(&p.tx) {
+                Ok(()) => self.pending.push(p),
+                Err(err) => {
+                    log::debug!("dropping mempool tx {}: {:?}", p.txhash, err);
+                    dropped.push(DroppedTx {
+                        tx: p.tx,
+                        reason: err.to_string(),
+                    });
+                }
+            }
+        }
+        self.provisional_state = state;
+        self.txx_in_state = self.pending.iter().map(|p| p.txhash).collect();
+        self.next_weight = self.pending.iter().map(|p| p.weight).sum();
+        dropped
+    }
+
+    /// Rebases the mempool onto the given state. Pending transactions included in the state's block are removed, and the rest are re-applied on top of it. Returns the transactions that no longer apply, e.g. because they conflict with the block.
+    pub fn rebase(&mut self, state: SealedState<MeshaCas>) -> Vec<DroppedTx> {
         log::trace!(
-            "forcibly rebasing mempool {} => {}",
-            current_sealed.header().height,
+            "rebasing mempool with {} txx onto {}",
+            self.pending.len(),
             state.header().height
         );
-        if !current_sealed.is_empty() {
-            let transactions = current_sealed.to_block().transactions;
-            log::warn!("*** THROWING AWAY {} MEMPOOL TXX ***", transactions.len());
+        let confirmed: HashSet<TxHash> = state
+            .to_block()
+            .transactions
+            .iter()
+            .map(|tx| tx.hash_nosigs())
+            .collect();
+        self.last_rebase = state.next_unsealed();
+        self.pending.retain(|p| !confirmed.contains(&p.txhash));
+        let dropped = self.rebuild_provisional();
+        if !dropped.is_empty() {
+            log::debug!("rebase invalidated {} mempool txx", dropped.len());
+        }
+        for dropped in dropped.iter() {
+            let txhash = dropped.tx.hash_nosigs();
+            self.remember(txhash, dropped.tx.clone());
+            self.dropped.put(txhash, dropped.reason.clone());
         }
+        self.report_metrics();
+        dropped
+    }
+
+    /// Exports the size of the mempool to Prometheus.
+    fn report_metrics(&self) {
+        #[cfg(feature = "metrics")]
+        {
+            crate::metrics::MEMPOOL_WEIGHT.set(self.next_weight as i64);
+            crate::metrics::MEMPOOL_TX_COUNT.set(self.txx_in_state.len() as i64);
+        }
+    }
+
+    /// Checks whether a transaction is currently pending in the mempool.
+    pub fn is_pending(&self, txhash: TxHash) -> bool {
+        self.txx_in_state.contains(&txhash)
+    }
 
-        let next_state = state.next_unsealed();
-        self.provisional_state = next_state.clone();
-        self.last_rebase = next_state;
-        self.txx_in_state.clear();
-        self.next_weight = 0;
+    /// Returns why a transaction was recently dropped from the mempool, if it was.
+    pub fn dropped_reason(&self, txhash: TxHash) -> Option<String> {
+        self.dropped.peek(&txhash).cloned()
     }
 
-    /// Lookups a recent transaction.
-    pub fn lookup_recent_tx(&self, _hash: TxHash) -> Option<Transaction> {
+    /// Lists the pending transactions that either create coins for, or spend coins from, the given address.
+    pub fn pending_touching(&self, address: Address) -> Vec<Transaction> {
+        self.pending
+            .iter()
+            .filter(|p| {
+                p.tx.outputs.iter().any(|o| o.covhash == address)
+                    || p.tx.covenants_as_map().contains_key(&address)
+            })
+            .map(|p| p.tx.clone())
+            .collect()
+    }
+
+    /// Remembers a transaction in the recently-seen store.
+    fn remember(&mut self, txhash: TxHash, tx: Transaction) {
+        self.recent.put(txhash, (tx, Instant::now()));
+    }
+
+    /// Lookups a recent transaction: either one that is still pending, or one seen within the last few minutes (including those dropped by a rebase).
+    pub fn lookup_recent_tx(&self, hash: TxHash) -> Option<Transaction> {
+        if let Some((tx, seen)) = self.recent.peek(&hash) {
+            if seen.elapsed() < RECENT_TX_WINDOW {
+                return Some(tx.clone());
+            }
+        }
+        if self.txx_in_state.contains(&hash) {
+            return self
+                .pending
+                .iter()
+                .find(|p| p.txhash == hash)
+                .map(|p| p.tx.clone());
+        }
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use bytes::Bytes;
+    use melstructs::{CoinID, CoinValue, Transaction, TxHash};
+
+    use super::{eviction_victims, PendingTx};
+
+    /// A pending transaction with the given fee and weight, spending the first output of each of `parents`.
+    fn pending(name: &str, fee: u128, weight: u128, parents: &[&PendingTx]) -> PendingTx {
+        let tx = Transaction {
+            inputs: parents
+                .iter()
+                .map(|p| CoinID {
+                    txhash: p.txhash,
+                    index: 0,
+                })
+                .collect(),
+            fee: CoinValue(fee),
+            data: Bytes::copy_from_slice(name.as_bytes()),
+            ..Default::default()
+        };
+        PendingTx {
+            txhash: tx.hash_nosigs(),
+            weight,
+            tx,
+        }
+    }
+
+    fn hashes(txx: &[&PendingTx]) -> HashSet<TxHash> {
+        txx.iter().map(|p| p.txhash).collect()
+    }
+
+    #[test]
+    fn evicts_parent_with_children() {
+        let parent = pending("parent", 100, 100, &[]);
+        let child = pending("child", 150, 100, &[&parent]);
+        let grandchild = pending("grandchild", 120, 100, &[&child]);
+        let other = pending("other", 1000, 100, &[]);
+        let new = pending("new", 1100, 100, &[]);
+        let victims = eviction_victims(
+            &[parent.clone(), child.clone(), grandchild.clone(), other],
+            &new,
+            50,
+        );
+        // evicting the parent alone frees enough, but its dependents go with it
+        assert_eq!(victims, Some(hashes(&[&parent, &child, &grandchild])));
+    }
+
+    #[test]
+    fn keeps_parent_with_better_paying_children() {
+        let parent = pending("parent", 100, 100, &[]);
+        let child = pending("child", 5000, 100, &[&parent]);
+        let other = pending("other", 200, 100, &[]);
+        let new = pending("new", 300, 100, &[]);
+        let pool = [parent.clone(), child.clone(), other.clone()];
+        // the parent pays least, but evicting it would take the child, which pays more than `new`
+        assert_eq!(eviction_victims(&pool, &new, 100), Some(hashes(&[&other])));
+        assert_eq!(eviction_victims(&pool, &new, 150), None);
+    }
+
+    #[test]
+    fn rejects_new_spending_from_victim() {
+        let parent = pending("parent", 100, 100, &[]);
+        let other = pending("other", 10_000, 100, &[]);
+        let new = pending("new", 1000, 100, &[&parent]);
+        assert_eq!(eviction_victims(&[parent, other], &new, 100), None);
+    }
+
+    #[test]
+    fn rejects_new_paying_no_better() {
+        let cheap = pending("cheap", 100, 100, &[]);
+        let new = pending("new", 100, 100, &[]);
+        assert_eq!(eviction_victims(&[cheap], &new, 100), None);
+    }
+}
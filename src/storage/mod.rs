@@ -1,8 +1,15 @@
+mod gc;
 mod mempool;
 mod smt;
+mod snapshot;
+mod stakes;
+mod verify;
 
 #[allow(clippy::module_inception)]
 mod storage;
 
+pub use gc::CompactionStats;
 pub use smt::*;
+pub use snapshot::SnapshotMeta;
 pub use storage::*;
+pub use verify::{verify_blocks, vote_weights, VerifiedBlock, VoteTally};
@@ -0,0 +1,84 @@
+use std::io::{Read, Write};
+
+use melstructs::{Block, BlockHeight, ConsensusProof, StakeDoc, TxHash};
+use novasmt::Hashed;
+use serde::{Deserialize, Serialize};
+
+/// Magic bytes at the start of every snapshot file, including a format version.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"MELSNAP1";
+
+/// Everything in a state snapshot except for the SMT nodes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    /// The block whose state the snapshot is of.
+    pub block: Block,
+    pub proof: ConsensusProof,
+    /// Every row of the stakes table up to the block, as (staking txhash, height, stake doc).
+    pub stakes: Vec<(TxHash, BlockHeight, StakeDoc)>,
+}
+
+/// Writes a snapshot file: the magic bytes, then the length-prefixed stdcode [SnapshotMeta], then any number of SMT nodes, each as its hash followed by its length-prefixed raw bytes.
+pub struct SnapshotWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    /// Starts a snapshot with the given metadata.
+    pub fn new(mut out: W, meta: &SnapshotMeta) -> anyhow::Result<Self> {
+        let meta = stdcode::serialize(meta)?;
+        out.write_all(SNAPSHOT_MAGIC)?;
+        out.write_all(&(meta.len() as u64).to_le_bytes())?;
+        out.write_all(&meta)?;
+        Ok(Self { out })
+    }
+
+    /// Adds an SMT node to the snapshot.
+    pub fn write_node(&mut self, hash: Hashed, raw: &[u8]) -> anyhow::Result<()> {
+        self.out.write_all(&hash)?;
+        self.out.write_all(&(raw.len() as u32).to_le_bytes())?;
+        self.out.write_all(raw)?;
+        Ok(())
+    }
+
+    /// Finishes the snapshot, flushing everything out.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads a snapshot file written by [SnapshotWriter].
+pub struct SnapshotReader<R: Read> {
+    input: R,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// Opens a snapshot, reading its metadata. The SMT nodes can then be read with [SnapshotReader::next_node].
+    pub fn open(mut input: R) -> anyhow::Result<(SnapshotMeta, Self)> {
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != SNAPSHOT_MAGIC {
+            anyhow::bail!("not a melnode snapshot, or an unsupported version of one")
+        }
+        let mut len = [0u8; 8];
+        input.read_exact(&mut len)?;
+        let mut meta = vec![0u8; u64::from_le_bytes(len) as usize];
+        input.read_exact(&mut meta)?;
+        Ok((stdcode::deserialize(&meta)?, Self { input }))
+    }
+
+    /// Reads the next SMT node, returning `None` at the end of the snapshot.
+    pub fn next_node(&mut self) -> anyhow::Result<Option<(Hashed, Vec<u8>)>> {
+        let mut hash = [0u8; 32];
+        match self.input.read_exact(&mut hash) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let mut len = [0u8; 4];
+        self.input.read_exact(&mut len)?;
+        let mut raw = vec![0u8; u32::from_le_bytes(len) as usize];
+        self.input.read_exact(&mut raw)?;
+        Ok(Some((hash, raw)))
+    }
+}
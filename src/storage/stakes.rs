@@ -0,0 +1,213 @@
+use std::{collections::BTreeMap, ops::Bound};
+
+use melstructs::{BlockHeight, StakeDoc, TxHash};
+use tip911_stakeset::StakeSet;
+use tmelcrypt::HashVal;
+
+use super::StateError;
+
+/// How many heights with new stakes there are between the full stakesets kept by [StakeHistory].
+const SNAPSHOT_INTERVAL: usize = 64;
+
+/// The stakes table in memory. Keeps the stakes added at every height where there were any, plus the full stakeset every [SNAPSHOT_INTERVAL] such heights, so that the stakeset at any height can be rebuilt by replaying a bounded number of changes.
+#[derive(Default)]
+pub struct StakeHistory {
+    added: BTreeMap<BlockHeight, Vec<(TxHash, StakeDoc)>>,
+    /// Stakesets right after some of the heights in `added`, without the stakes already unlocked by then.
+    snapshots: BTreeMap<BlockHeight, StakeSet>,
+}
+
+impl StakeHistory {
+    /// Builds the history out of rows of the stakes table, as (staking txhash, height, stake doc), in any order.
+    pub fn new(rows: impl IntoIterator<Item = (TxHash, BlockHeight, StakeDoc)>) -> Self {
+        let mut by_height: BTreeMap<BlockHeight, Vec<(TxHash, StakeDoc)>> = BTreeMap::new();
+        for (txhash, height, doc) in rows {
+            by_height.entry(height).or_default().push((txhash, doc));
+        }
+        let mut history = Self::default();
+        for (height, stakes) in by_height {
+            history.add(height, stakes);
+        }
+        history
+    }
+
+    /// Records the stakes added at a height above all the heights recorded so far.
+    pub fn add(&mut self, height: BlockHeight, stakes: Vec<(TxHash, StakeDoc)>) {
+        if stakes.is_empty() {
+            return;
+        }
+        self.added.insert(height, stakes);
+        let since_snapshot = match self.snapshots.keys().next_back() {
+            Some(last) => self
+                .added
+                .range((Bound::Excluded(*last), Bound::Unbounded))
+                .count(),
+            None => self.added.len(),
+        };
+        if since_snapshot >= SNAPSHOT_INTERVAL {
+            let stakeset = self.get(height);
+            self.snapshots.insert(height, stakeset);
+        }
+    }
+
+    /// Forgets the stakes added at a height, undoing [StakeHistory::add] when the block they came with could not be stored after all.
+    pub fn remove(&mut self, height: BlockHeight) {
+        self.added.remove(&height);
+        self.snapshots.remove(&height);
+    }
+
+    /// Rebuilds the stakeset at the given height, starting from the last full stakeset at or below it.
+    pub fn get(&self, height: BlockHeight) -> StakeSet {
+        let (start, mut stakeset) = match self.snapshots.range(..=height).next_back() {
+            Some((start, stakeset)) => (Bound::Excluded(*start), stakeset.clone()),
+            None => (Bound::Unbounded, StakeSet::new(vec![].into_iter())),
+        };
+        for (_, stakes) in self.added.range((start, Bound::Included(height))) {
+            for (txhash, doc) in stakes {
+                stakeset.add_stake(*txhash, doc.clone());
+            }
+        }
+        // stakes unlocked as of a snapshot stay unlocked later on, so it's fine that snapshots already lack them
+        stakeset.unlock_old(height.epoch());
+        stakeset
+    }
+
+    /// Rebuilds the stakeset at the given height like [StakeHistory::get], checking it against the stakes hash in the header at that height, since a damaged stakes table would otherwise silently produce wrong states.
+    pub fn get_checked(
+        &self,
+        height: BlockHeight,
+        stakes_hash: HashVal,
+    ) -> Result<StakeSet, StateError> {
+        let stakeset = self.get(height);
+        if HashVal(stakeset.pre_tip911().root_hash()) != stakes_hash {
+            log::error!("stakes table is inconsistent with block {height}");
+            return Err(StateError::StakesMismatch(height));
+        }
+        Ok(stakeset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use melstructs::{BlockHeight, CoinValue, StakeDoc, TxHash, STAKE_EPOCH};
+    use tip911_stakeset::StakeSet;
+    use tmelcrypt::{Ed25519PK, HashVal};
+
+    use super::{StakeHistory, StateError, SNAPSHOT_INTERVAL};
+
+    type Row = (TxHash, BlockHeight, StakeDoc);
+
+    /// Rows of a stakes table with stakes added at `count` heights spread over a few epochs, some of which unlock along the way.
+    fn random_rows(rng: &fastrand::Rng, count: usize) -> Vec<Row> {
+        let mut rows = vec![];
+        let mut height = 0;
+        for _ in 0..count {
+            for _ in 0..rng.usize(1..4) {
+                let e_start = height / STAKE_EPOCH;
+                rows.push((
+                    TxHash(HashVal::random()),
+                    BlockHeight(height),
+                    StakeDoc {
+                        pubkey: Ed25519PK(HashVal::random().0),
+                        e_start,
+                        e_post_end: e_start + rng.u64(1..4),
+                        syms_staked: CoinValue(rng.u128(1..1 << 64)),
+                    },
+                ));
+            }
+            height += rng.u64(1..STAKE_EPOCH / 10);
+        }
+        rng.shuffle(&mut rows);
+        rows
+    }
+
+    /// The stakeset at a height, replayed from scratch out of every row up to it.
+    fn replayed(rows: &[Row], height: BlockHeight) -> StakeSet {
+        let mut stakeset = StakeSet::new(vec![].into_iter());
+        let mut rows: Vec<&Row> = rows.iter().filter(|row| row.1 <= height).collect();
+        rows.sort_by_key(|row| row.1);
+        for (txhash, _, doc) in rows {
+            stakeset.add_stake(*txhash, doc.clone());
+        }
+        stakeset.unlock_old(height.epoch());
+        stakeset
+    }
+
+    fn stakes_hash(stakeset: &StakeSet) -> HashVal {
+        HashVal(stakeset.pre_tip911().root_hash())
+    }
+
+    #[test]
+    fn history_matches_replay() {
+        let rng = fastrand::Rng::with_seed(0x57a4e);
+        let rows = random_rows(&rng, SNAPSHOT_INTERVAL * 3 + 5);
+        let history = StakeHistory::new(rows.clone());
+        let max_height = rows.iter().map(|row| row.1).max().unwrap();
+        let mut heights: Vec<BlockHeight> = rows.iter().map(|row| row.1).collect();
+        heights.extend((0..100).map(|_| BlockHeight(rng.u64(..=max_height.0 + STAKE_EPOCH))));
+        for height in heights {
+            assert_eq!(
+                stakes_hash(&history.get(height)),
+                stakes_hash(&replayed(&rows, height)),
+                "at {height}"
+            );
+        }
+    }
+
+    #[test]
+    fn removing_undoes_adding() {
+        let rng = fastrand::Rng::with_seed(0x4e40);
+        let rows = random_rows(&rng, SNAPSHOT_INTERVAL * 2);
+        let mut history = StakeHistory::new(rows.clone());
+        let top = rows.iter().map(|row| row.1).max().unwrap();
+        let before = stakes_hash(&history.get(top + BlockHeight(1)));
+        // enough new heights to take a snapshot, all of which then fail to be stored
+        let extra_heights: Vec<BlockHeight> = (1..=SNAPSHOT_INTERVAL as u64)
+            .map(|i| top + BlockHeight(i))
+            .collect();
+        for (height, (txhash, _, doc)) in extra_heights
+            .iter()
+            .zip(random_rows(&rng, SNAPSHOT_INTERVAL))
+        {
+            history.add(*height, vec![(txhash, doc)]);
+        }
+        assert!(history
+            .snapshots
+            .contains_key(extra_heights.last().unwrap()));
+        for height in extra_heights {
+            history.remove(height);
+        }
+        assert_eq!(stakes_hash(&history.get(top + BlockHeight(1))), before);
+    }
+
+    #[test]
+    fn corrupted_table_is_caught() {
+        let rng = fastrand::Rng::with_seed(0xbad);
+        for _ in 0..20 {
+            let mut rows = random_rows(&rng, SNAPSHOT_INTERVAL + 10);
+            // stakes that never unlock, so that corrupting any of them changes the stakeset
+            for row in rows.iter_mut() {
+                row.2.e_post_end = u64::MAX;
+            }
+            let height = rows.iter().map(|row| row.1).max().unwrap();
+            let expected = stakes_hash(&replayed(&rows, height));
+            assert!(StakeHistory::new(rows.clone())
+                .get_checked(height, expected)
+                .is_ok());
+
+            let victim = rng.usize(..rows.len());
+            match rng.u8(0..4) {
+                0 => {
+                    rows.remove(victim);
+                }
+                1 => rows[victim].2.syms_staked += CoinValue(1),
+                2 => rows[victim].2.pubkey = Ed25519PK(HashVal::random().0),
+                _ => rows[victim].1 = height + BlockHeight(1),
+            }
+            assert!(matches!(
+                StakeHistory::new(rows).get_checked(height, expected),
+                Err(StateError::StakesMismatch(h)) if h == height
+            ));
+        }
+    }
+}
@@ -1,10 +1,13 @@
 use anyhow::Context;
 use event_listener::Event;
 use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
 use smol::channel::{Receiver, Sender};
 use std::{
+    collections::HashSet,
+    future::Future,
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
     time::Instant,
 };
@@ -14,14 +17,61 @@ use tip911_stakeset::StakeSet;
 use tmelcrypt::HashVal;
 
 use moka::sync::Cache;
+use novasmt::{ContentAddrStore, Hashed, Tree};
 use parking_lot::RwLock;
 
-use melstf::{GenesisConfig, SealedState};
-use melstructs::{Block, BlockHeight, CoinValue, ConsensusProof, NetID, StakeDoc, TxHash, TxKind};
+use melstf::{GenesisConfig, SealedState, SmtMapping};
+use melstructs::{
+    AbbrBlock, Block, BlockHeight, Checkpoint, ConsensusProof, Header, StakeDoc, TxHash,
+};
 
 use crate::autoretry::autoretry;
 
-use super::{mempool::Mempool, MeshaCas};
+use super::{
+    gc,
+    mempool::Mempool,
+    snapshot::{SnapshotMeta, SnapshotReader, SnapshotWriter},
+    stakes::StakeHistory,
+    vote_weights, CompactionStats, MeshaCas, VerifiedBlock, VoteTally,
+};
+
+/// Key in the `misc` table recording that heights below its value still need to be added to the transaction index.
+const TXINDEX_BACKFILL_KEY: &str = "txindex_backfill_end";
+
+/// How many blocks are added to the transaction index per backfill batch.
+const TXINDEX_BACKFILL_BATCH: u64 = 1000;
+
+/// Key in the `misc` table recording that pruning has orphaned SMT nodes, which stay around until the forest is compacted.
+const FOREST_GC_PENDING_KEY: &str = "forest_gc_pending";
+
+/// How many blocks are pruned per batch.
+const PRUNE_BATCH: u64 = 1000;
+
+/// How many new blocks accumulate between pruning passes.
+const PRUNE_INTERVAL: u64 = 100;
+
+/// How many missing SMT nodes are fetched at once when importing a state.
+const IMPORT_FETCH_BATCH: usize = 1000;
+
+/// Why a state could not be reconstructed from storage.
+#[derive(thiserror::Error, Debug, Clone, Serialize, Deserialize)]
+pub enum StateError {
+    #[error("no block at height {0}")]
+    Missing(BlockHeight),
+    #[error("the stakes table does not match the stakes hash of block {0}")]
+    StakesMismatch(BlockHeight),
+}
+
+/// Why a block could not be applied.
+#[derive(thiserror::Error, Debug)]
+pub enum ApplyBlockError {
+    /// The block itself is bad: it doesn't follow the highest block, lacks votes, or isn't a valid state transition.
+    #[error("invalid block: {0:#}")]
+    Invalid(anyhow::Error),
+    /// Our own storage failed, so the block may well be fine.
+    #[error("cannot apply block: {0:#}")]
+    Local(anyhow::Error),
+}
 
 /// Storage encapsulates all storage used by a Mel full node (replica or staker).
 #[derive(Clone)]
@@ -29,6 +79,10 @@ pub struct Storage {
     send_pool: Sender<rusqlite::Connection>,
     recv_pool: Receiver<rusqlite::Connection>,
     old_cache: Arc<Cache<BlockHeight, Block>>,
+    /// Stakesets of recent heights, either taken straight from the states [Storage::apply_block] produces or checked against their headers.
+    stakeset_cache: Arc<Cache<BlockHeight, StakeSet>>,
+    /// The stakes table in memory. Only changes when stake transactions land.
+    stake_history: Arc<RwLock<StakeHistory>>,
     forest: Arc<novasmt::Database<MeshaCas>>,
 
     genesis: GenesisConfig,
@@ -42,6 +96,9 @@ pub struct Storage {
     sqlite_path: PathBuf,
 
     lock: Arc<smol::lock::Mutex<()>>,
+
+    /// If set, only this many of the newest blocks are kept in full.
+    prune_keep: Option<u64>,
 }
 
 impl Storage {
@@ -60,13 +117,13 @@ impl Storage {
         format!("{}.coinindex.db", path.to_string_lossy()).into()
     }
 
-    /// Opens a NodeStorage, given a meshanina and boringdb database.
-    pub async fn open(mut db_folder: PathBuf, genesis: GenesisConfig) -> anyhow::Result<Self> {
-        let genesis_id = tmelcrypt::hash_single(stdcode::serialize(&genesis).unwrap());
-        db_folder.push(format!("{}/", hex::encode(genesis_id.0)));
-        std::fs::create_dir_all(&db_folder).context("cannot make folder")?;
-        let sqlite_path = db_folder.clone().tap_mut(|path| path.push("storage.db"));
-        let mesha_path = db_folder.clone().tap_mut(|path| path.push("merkle.db"));
+    /// Opens a NodeStorage, given a meshanina and boringdb database. If `prune_keep` is given, only that many of the newest blocks are kept in full; older blocks are reduced to their abbreviated form.
+    pub async fn open(
+        db_folder: PathBuf,
+        genesis: GenesisConfig,
+        prune_keep: Option<u64>,
+    ) -> anyhow::Result<Self> {
+        let (sqlite_path, mesha_path) = db_paths(db_folder, &genesis)?;
         log::debug!("about to sqlite");
         let conn = rusqlite::Connection::open(&sqlite_path).context("cannot make sqlite")?;
         conn.execute("create table if not exists history (height primary key not null, header not null, block not null)", params![])?;
@@ -79,6 +136,34 @@ impl Storage {
             "create table if not exists misc (key primary key not null, value not null)",
             params![],
         )?;
+        conn.execute(
+            "create table if not exists transaction_smts (height primary key not null, root not null)",
+            params![],
+        )?;
+        conn.execute(
+            "create table if not exists abbr_history (height primary key not null, abbr_block not null)",
+            params![],
+        )?;
+        let txindex_exists: bool = conn.query_row(
+            "select count(*) > 0 from sqlite_master where type = 'table' and name = 'txindex'",
+            params![],
+            |r| r.get(0),
+        )?;
+        conn.execute(
+            "create table if not exists txindex (txhash primary key not null, height not null, idx not null)",
+            params![],
+        )?;
+        if !txindex_exists {
+            // blocks applied before the transaction index existed need to be indexed in the background
+            let highest: Option<u64> =
+                conn.query_row("select max(height) from history", params![], |r| r.get(0))?;
+            if let Some(highest) = highest {
+                conn.execute(
+                    "insert into misc (key, value) values ($1, $2)",
+                    params![TXINDEX_BACKFILL_KEY, highest + 1],
+                )?;
+            }
+        }
 
         log::debug!("sqlite initted");
 
@@ -90,6 +175,9 @@ impl Storage {
             )?;
         }
 
+        let stake_history =
+            StakeHistory::new(read_stake_rows(&conn, BlockHeight(i64::MAX as u64))?);
+
         let (send_pool, recv_pool) = smol::channel::unbounded();
         for _ in 0..16 {
             let conn = rusqlite::Connection::open(&sqlite_path)?;
@@ -98,15 +186,27 @@ impl Storage {
             send_pool.send(conn).await.unwrap();
         }
 
+        let gc_pending: bool = conn.query_row(
+            "select count(*) > 0 from misc where key = $1",
+            params![FOREST_GC_PENDING_KEY],
+            |r| r.get(0),
+        )?;
+        if gc_pending {
+            // collecting them means copying the whole forest, which is too slow to do before serving anything
+            log::info!("pruning left SMT nodes behind in {:?}; stop melnode and run melnode-compact to reclaim the space", mesha_path);
+        }
+
         log::debug!("about to mesha");
         let forest = novasmt::Database::new(MeshaCas::new(
             meshanina::Mapping::open(&mesha_path).context("cannot open mesha")?,
         ));
         let mempool = Arc::new(Mempool::new(genesis.clone().realize(&forest)).into());
-        Ok(Self {
+        let storage = Self {
             send_pool,
             recv_pool,
             old_cache: Arc::new(Cache::new(100)),
+            stakeset_cache: Arc::new(Cache::new(100)),
+            stake_history: Arc::new(stake_history.into()),
             forest: Arc::new(forest),
 
             genesis,
@@ -116,17 +216,186 @@ impl Storage {
             sqlite_path,
 
             lock: Default::default(),
+            prune_keep,
+        };
+        smolscale::spawn(storage.clone().backfill_tx_index()).detach();
+        if let Some(keep) = storage.prune_keep {
+            smolscale::spawn(storage.clone().prune_loop(keep)).detach();
+        }
+        Ok(storage)
+    }
+
+    /// Compacts the SMT forest of a database that is not currently open, rewriting `merkle.db` with only the nodes reachable from the states still in the history. Nodes left behind by abandoned mempool states, undecided staker proposals, and pruning are all dropped.
+    pub fn compact(db_folder: PathBuf, genesis: &GenesisConfig) -> anyhow::Result<CompactionStats> {
+        let (sqlite_path, mesha_path) = db_paths(db_folder, genesis)?;
+        if !mesha_path.exists() {
+            anyhow::bail!("no SMT forest at {:?}", mesha_path);
+        }
+        let conn = rusqlite::Connection::open(&sqlite_path).context("cannot open sqlite")?;
+        compact_forest(&conn, &mesha_path)
+    }
+
+    /// Checks whether pruning has left SMT nodes behind in a database that is not currently open, which [Storage::compact] would reclaim.
+    pub fn has_pruned_garbage(db_folder: PathBuf, genesis: &GenesisConfig) -> anyhow::Result<bool> {
+        let (sqlite_path, _) = db_paths(db_folder, genesis)?;
+        let conn = rusqlite::Connection::open(&sqlite_path).context("cannot open sqlite")?;
+        let has_misc: bool = conn.query_row(
+            "select count(*) > 0 from sqlite_master where type = 'table' and name = 'misc'",
+            params![],
+            |r| r.get(0),
+        )?;
+        if !has_misc {
+            return Ok(false);
+        }
+        Ok(conn.query_row(
+            "select count(*) > 0 from misc where key = $1",
+            params![FOREST_GC_PENDING_KEY],
+            |r| r.get(0),
+        )?)
+    }
+
+    /// Keeps pruning blocks older than the newest `keep`, in a pass every [PRUNE_INTERVAL] blocks.
+    async fn prune_loop(self, keep: u64) {
+        loop {
+            let highest = self.highest_height().await;
+            let cutoff = BlockHeight(highest.0.saturating_sub(keep) + 1);
+            while self.prune_below(cutoff).await {}
+            self.wait_height_above(BlockHeight(highest.0 + PRUNE_INTERVAL - 1))
+                .await;
+        }
+    }
+
+    /// Prunes one batch of blocks below the given height, replacing each with its abbreviated form and dropping its consensus proof and transaction SMT. Returns whether there may be more to prune.
+    async fn prune_below(&self, cutoff: BlockHeight) -> bool {
+        let more = autoretry(|| async {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let mut conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let conn = conn.transaction()?;
+                let mut pruned = 0;
+                let mut last = 0u64;
+                {
+                    let mut stmt = conn.prepare(
+                        "select height, block from history where height < $1 order by height limit $2",
+                    )?;
+                    let mut rows = stmt.query(params![cutoff.0, PRUNE_BATCH])?;
+                    while let Some(row) = rows.next()? {
+                        let height: u64 = row.get(0)?;
+                        let block: Block = stdcode::deserialize(&row.get::<_, Vec<u8>>(1)?)?;
+                        conn.execute(
+                            "insert into abbr_history (height, abbr_block) values ($1, $2) on conflict do nothing",
+                            params![height, block.abbreviate().stdcode()],
+                        )?;
+                        pruned += 1;
+                        last = height;
+                    }
+                }
+                if pruned == 0 {
+                    return anyhow::Ok(false);
+                }
+                conn.execute("delete from history where height <= $1", params![last])?;
+                conn.execute(
+                    "delete from consensus_proofs where height <= $1",
+                    params![last],
+                )?;
+                conn.execute(
+                    "delete from transaction_smts where height <= $1",
+                    params![last],
+                )?;
+                conn.execute(
+                    "insert into misc (key, value) values ($1, 1) on conflict do nothing",
+                    params![FOREST_GC_PENDING_KEY],
+                )?;
+                conn.commit()?;
+                log::debug!("pruned {pruned} blocks up to height {last}");
+                Ok(pruned == PRUNE_BATCH)
+            })
+            .await
         })
+        .await;
+        self.old_cache.invalidate_all();
+        more
+    }
+
+    /// Indexes the transactions of blocks that were applied before the transaction index existed, walking down from the newest such block to genesis.
+    async fn backfill_tx_index(self) {
+        loop {
+            let more = autoretry(|| async {
+                let conn = self.recv_pool.recv().await?;
+                let send_pool = self.send_pool.clone();
+                smol::unblock(move || {
+                    let mut conn =
+                        scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                    let conn = conn.transaction()?;
+                    let end: Option<u64> = conn
+                        .query_row(
+                            "select value from misc where key = $1",
+                            params![TXINDEX_BACKFILL_KEY],
+                            |r| r.get(0),
+                        )
+                        .optional()?;
+                    let end = match end {
+                        Some(end) => end,
+                        None => return anyhow::Ok(false),
+                    };
+                    let start = end.saturating_sub(TXINDEX_BACKFILL_BATCH).max(1);
+                    {
+                        let mut stmt = conn.prepare(
+                            "select block from history where height >= $1 and height < $2",
+                        )?;
+                        let mut rows = stmt.query(params![start, end])?;
+                        while let Some(row) = rows.next()? {
+                            let block: Block = stdcode::deserialize(&row.get::<_, Vec<u8>>(0)?)?;
+                            insert_tx_index(&conn, &block)?;
+                        }
+                    }
+                    if start <= 1 {
+                        conn.execute(
+                            "delete from misc where key = $1",
+                            params![TXINDEX_BACKFILL_KEY],
+                        )?;
+                    } else {
+                        conn.execute(
+                            "update misc set value = $2 where key = $1",
+                            params![TXINDEX_BACKFILL_KEY, start],
+                        )?;
+                    }
+                    conn.commit()?;
+                    log::debug!("transaction index backfilled down to height {start}");
+                    Ok(start > 1)
+                })
+                .await
+            })
+            .await;
+            if !more {
+                return;
+            }
+        }
     }
 
     /// Obtain the highest state.
-    pub async fn highest_state(&self) -> SealedState<MeshaCas> {
+    pub async fn highest_state(&self) -> Result<SealedState<MeshaCas>, StateError> {
         // TODO this may be a bit stale
         let height = self.highest_height().await;
         if height.0 > 0 {
-            self.get_state(height).await.expect("highest not available")
+            self.get_state(height).await
         } else {
-            self.genesis.clone().realize(self.forest()).seal(None)
+            Ok(self.genesis.clone().realize(self.forest()).seal(None))
+        }
+    }
+
+    /// Obtain the header of the highest block, without reconstructing its state.
+    pub async fn highest_header(&self) -> Header {
+        let height = self.highest_height().await;
+        match self.get_block(height).await {
+            Some(block) if height.0 > 0 => block.header,
+            _ => self
+                .genesis
+                .clone()
+                .realize(self.forest())
+                .seal(None)
+                .header(),
         }
     }
 
@@ -147,42 +416,60 @@ impl Storage {
         .unwrap_or_default()
     }
 
+    /// Obtain the lowest height with a full block, which is above genesis for nodes that prune old blocks or started from a trusted checkpoint.
+    pub async fn lowest_height(&self) -> BlockHeight {
+        autoretry(|| async {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let val: Option<u64> =
+                    conn.query_row("select min(height) from history", params![], |r| r.get(0))?;
+                anyhow::Ok(val.map(BlockHeight))
+            })
+            .await
+        })
+        .await
+        .unwrap_or_default()
+    }
+
     /// Waits until a certain height is available, then returns it.
     pub async fn get_state_or_wait(&self, height: BlockHeight) -> SealedState<MeshaCas> {
         loop {
             let notify = self.new_block_notify.listen();
             match self.get_state(height).await {
-                Some(val) => return val,
-                _ => notify.await,
+                Ok(val) => return val,
+                Err(StateError::Missing(_)) => notify.await,
+                Err(err) => {
+                    log::error!(
+                        "cannot get the state at {height}, retrying after the next block: {err}"
+                    );
+                    notify.await
+                }
+            }
+        }
+    }
+
+    /// Waits until a block above the given height is available, then returns the new highest height.
+    pub async fn wait_height_above(&self, height: BlockHeight) -> BlockHeight {
+        loop {
+            let notify = self.new_block_notify.listen();
+            let highest = self.highest_height().await;
+            if highest > height {
+                return highest;
             }
+            notify.await;
         }
     }
 
-    /// Reconstruct the stakeset at a given height.
-    async fn get_stakeset(&self, height: BlockHeight) -> StakeSet {
+    /// Obtain every row of the stakes table up to a given height.
+    async fn get_stake_rows(&self, height: BlockHeight) -> Vec<(TxHash, BlockHeight, StakeDoc)> {
         autoretry(|| async {
             let conn = self.recv_pool.recv().await?;
             let send_pool = self.send_pool.clone();
-            let genesis = self.genesis.clone();
             smol::unblock(move || {
                 let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
-                let mut stmt = conn
-                    .prepare("select txhash, height, stake_doc from stakes where height <= $1")?;
-                let mut stakes = StakeSet::new(vec![].into_iter());
-                // TODO this is dumb!
-                for (txhash, stake) in genesis.stakes {
-                    stakes.add_stake(txhash, stake);
-                }
-                for row in stmt.query_map(params![height.0], |row| {
-                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-                })? {
-                    let row: (String, u64, Vec<u8>) = row?;
-                    let t: TxHash = row.0.parse()?;
-                    let sd: StakeDoc = stdcode::deserialize(&row.2)?;
-                    stakes.add_stake(t, sd);
-                }
-                stakes.unlock_old(height.epoch());
-                anyhow::Ok(stakes)
+                read_stake_rows(&conn, height)
             })
             .await
         })
@@ -222,15 +509,133 @@ impl Storage {
         .await
     }
 
+    /// Obtain the abbreviated form of a block, together with its consensus proof. Unlike [Storage::get_block], this also works for pruned blocks, whose consensus proofs come back empty.
+    pub async fn get_abbr_block(&self, height: BlockHeight) -> Option<(AbbrBlock, ConsensusProof)> {
+        if let Some(block) = self.get_block(height).await {
+            let proof = self.get_consensus(height).await?;
+            return Some((block.abbreviate(), proof));
+        }
+        let abbr_block: Option<AbbrBlock> = autoretry(|| async {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let blob: Option<Vec<u8>> = conn
+                    .query_row(
+                        "select abbr_block from abbr_history where height = $1",
+                        params![height.0],
+                        |r| r.get(0),
+                    )
+                    .optional()?;
+                if let Some(blob) = blob {
+                    anyhow::Ok(Some(stdcode::deserialize(&blob)?))
+                } else {
+                    Ok(None)
+                }
+            })
+            .await
+        })
+        .await;
+        Some((abbr_block?, ConsensusProof::default()))
+    }
+
     /// Obtain a historical SealedState.
-    pub async fn get_state(&self, height: BlockHeight) -> Option<SealedState<MeshaCas>> {
-        let block: Block = self.get_block(height).await?;
-        let stakeset = self.get_stakeset(height).await;
-        assert_eq!(
-            HashVal(stakeset.pre_tip911().root_hash()),
-            block.header.stakes_hash
-        );
-        Some(SealedState::from_block(&block, &stakeset, &self.forest))
+    pub async fn get_state(
+        &self,
+        height: BlockHeight,
+    ) -> Result<SealedState<MeshaCas>, StateError> {
+        let block: Block = self
+            .get_block(height)
+            .await
+            .ok_or(StateError::Missing(height))?;
+        let stakeset = match self.stakeset_cache.get(&height) {
+            Some(stakeset) => stakeset,
+            None => {
+                let stakeset = self
+                    .stake_history
+                    .read()
+                    .get_checked(height, block.header.stakes_hash)?;
+                // only stakesets known to match their headers are cached, so that lookups racing with apply_block can't cache anything stale
+                self.stakeset_cache.insert(height, stakeset.clone());
+                stakeset
+            }
+        };
+        Ok(SealedState::from_block(&block, &stakeset, &self.forest))
+    }
+
+    /// Obtain the SMT of all the transactions confirmed at a given height, whose root is the header's `transactions_hash`.
+    pub async fn get_transactions_smt(&self, height: BlockHeight) -> Option<Tree<MeshaCas>> {
+        let root: Option<Vec<u8>> = autoretry(|| async {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let root: Option<Vec<u8>> = conn
+                    .query_row(
+                        "select root from transaction_smts where height = $1",
+                        params![height.0],
+                        |r| r.get(0),
+                    )
+                    .optional()?;
+                anyhow::Ok(root)
+            })
+            .await
+        })
+        .await;
+        if let Some(root) = root.and_then(|root| root.try_into().ok()) {
+            return self.forest.get_tree(root);
+        }
+
+        // blocks applied before the transaction SMTs were persisted get theirs built on demand, once
+        let block = self.get_block(height).await?;
+        let tree = self.insert_transactions_smt(&block);
+        self.forest.storage().flush();
+        let root = tree.root_hash();
+        autoretry(|| async {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                conn.execute(
+                    "insert into transaction_smts (height, root) values ($1, $2) on conflict do nothing",
+                    params![height.0, root.to_vec()],
+                )?;
+                anyhow::Ok(())
+            })
+            .await
+        })
+        .await;
+        Some(tree)
+    }
+
+    /// Inserts the SMT of all the transactions in a block into the forest.
+    fn insert_transactions_smt(&self, blk: &Block) -> Tree<MeshaCas> {
+        let mut mm = SmtMapping::new(self.forest.get_tree(Default::default()).unwrap());
+        for tx in blk.transactions.iter() {
+            mm.insert(tx.hash_nosigs(), tx.clone());
+        }
+        mm.mapping
+    }
+
+    /// Obtain the height of a confirmed transaction, together with its index within that block's transactions sorted by hash.
+    pub async fn get_tx_location(&self, txhash: TxHash) -> Option<(BlockHeight, u32)> {
+        autoretry(|| async {
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            smol::unblock(move || {
+                let conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let res: Option<(u64, u32)> = conn
+                    .query_row(
+                        "select height, idx from txindex where txhash = $1",
+                        params![txhash.to_string()],
+                        |r| Ok((r.get(0)?, r.get(1)?)),
+                    )
+                    .optional()?;
+                anyhow::Ok(res.map(|(height, idx)| (BlockHeight(height), idx)))
+            })
+            .await
+        })
+        .await
     }
 
     /// Obtain a historical ConsensusProof.
@@ -259,55 +664,86 @@ impl Storage {
     }
 
     /// Consumes a block, applying it to the current state.
-    pub async fn apply_block(&self, blk: Block, cproof: ConsensusProof) -> anyhow::Result<()> {
+    pub async fn apply_block(
+        &self,
+        blk: Block,
+        cproof: ConsensusProof,
+    ) -> Result<(), ApplyBlockError> {
+        let verified = smol::unblock(move || VerifiedBlock::new(blk, cproof)).await;
+        self.apply_verified_block(verified).await
+    }
+
+    /// Consumes a block whose signatures were already checked, usually in parallel with other blocks by [super::verify_blocks]. Only the cheap stake tally and the state transition itself happen under the lock.
+    pub async fn apply_verified_block(
+        &self,
+        verified: VerifiedBlock,
+    ) -> Result<(), ApplyBlockError> {
+        let VerifiedBlock {
+            block: blk,
+            proof: cproof,
+            signers,
+        } = verified;
         let _guard = self.lock.lock().await;
         if blk.header.height.0 == 531 {
             eprintln!("APPLY BLOCK: {:#?}", blk);
         }
-        let highest_state = self.highest_state().await;
+        let highest_state = self
+            .highest_state()
+            .await
+            .map_err(|err| ApplyBlockError::Local(err.into()))?;
         let header = blk.header;
         if header.height != highest_state.header().height + 1.into() {
-            anyhow::bail!(
+            return Err(ApplyBlockError::Invalid(anyhow::anyhow!(
                 "cannot apply block {} to height {}",
                 header.height,
                 highest_state.header().height
-            );
+            )));
         }
 
         // Check the consensus proof
-        let mut total_votes = CoinValue(0);
-        let mut present_votes = CoinValue(0);
-        for stake_doc_bytes in highest_state.raw_stakes().pre_tip911().iter() {
-            let stake_doc: StakeDoc = stdcode::deserialize(&stake_doc_bytes.1)?;
-            if blk.header.height.epoch() >= stake_doc.e_start
-                && blk.header.height.epoch() < stake_doc.e_post_end
-            {
-                total_votes += stake_doc.syms_staked;
-                if let Some(v) = cproof.get(&stake_doc.pubkey) {
-                    if stake_doc.pubkey.verify(&blk.header.hash(), v) {
-                        present_votes += total_votes;
-                    }
-                }
-            }
-        }
-        if present_votes.0 <= 2 * total_votes.0 / 3 {
-            anyhow::bail!(
+        let weights = vote_weights(&highest_state).map_err(ApplyBlockError::Local)?;
+        let tally = VoteTally::new(&weights, &signers);
+        if !tally.has_quorum() {
+            return Err(ApplyBlockError::Invalid(anyhow::anyhow!(
                 "rejecting putative block {} due to insufficient votes ({}/{})",
                 blk.header.height,
-                present_votes,
-                total_votes
-            )
+                tally.present,
+                tally.total
+            )));
         }
 
         let start = Instant::now();
-        let new_state = highest_state.apply_block(&blk)?;
+        let new_state = highest_state
+            .apply_block(&blk)
+            .map_err(|err| ApplyBlockError::Invalid(err.into()))?;
+        let transactions_root = self.insert_transactions_smt(&blk).root_hash();
+        // whatever stakes melstf added while applying the block are exactly the new rows of the stakes table
+        let old_stakes: HashSet<TxHash> = highest_state
+            .raw_stakes()
+            .iter()
+            .map(|(txhash, _)| *txhash)
+            .collect();
+        let new_stakes: Vec<(TxHash, StakeDoc)> = new_state
+            .raw_stakes()
+            .iter()
+            .filter(|(txhash, _)| !old_stakes.contains(txhash))
+            .map(|(txhash, doc)| (*txhash, doc.clone()))
+            .collect();
         // we flush the merkle stuff first, because the sqlite points to merkle
         self.forest.storage().flush();
         let apply_time = start.elapsed();
         let start = Instant::now();
 
+        // the stakes in memory must be up to date by the time the new block becomes visible
+        let new_height = new_state.header().height;
+        self.stake_history
+            .write()
+            .add(new_height, new_stakes.clone());
+        self.stakeset_cache
+            .insert(new_height, new_state.raw_stakes().clone());
+
         // now transactionally save to sqlite
-        {
+        let stored = async {
             let conn = self.recv_pool.recv().await?;
             let send_pool = self.send_pool.clone();
             let _forest = self.forest.clone();
@@ -324,20 +760,29 @@ impl Storage {
                     params![blk.header.height.0, stdcode::serialize(&cproof).unwrap()],
                 )?;
 
-                for txn in blk.transactions {
-                    if txn.kind == TxKind::Stake {
-                        if let Ok(doc) = stdcode::deserialize::<StakeDoc>(&txn.data) {
-                            // TODO BUG BUG this poorly replicates the validation logic. Make a method SealedState::new_stakes()
-                            if blk.header.height.0 >= 500000 || blk.header.network != NetID::Mainnet {
-                            conn.execute("insert into stakes (txhash, height, stake_doc) values ($1, $2, $3)", params![txn.hash_nosigs().to_string(), blk.header.height.0, doc.stdcode()])?;
-                            }
-                        }
-                    }
+                conn.execute(
+                    "insert into transaction_smts (height, root) values ($1, $2)",
+                    params![blk.header.height.0, transactions_root.to_vec()],
+                )?;
+
+                insert_tx_index(&conn, &blk)?;
+
+                for (txhash, doc) in new_stakes {
+                    conn.execute(
+                        "insert into stakes (txhash, height, stake_doc) values ($1, $2, $3)",
+                        params![txhash.to_string(), blk.header.height.0, doc.stdcode()],
+                    )?;
                 }
                 conn.commit()?;
                 anyhow::Ok(())
             })
-            .await?
+            .await
+        }
+        .await;
+        if let Err(err) = stored {
+            self.stake_history.write().remove(new_height);
+            self.stakeset_cache.invalidate(&new_height);
+            return Err(ApplyBlockError::Local(err));
         }
         log::debug!(
             "applied block {} / {} in {:.2}ms (history insertion {:.2}ms)",
@@ -346,15 +791,384 @@ impl Storage {
             apply_time.as_secs_f64() * 1000.0,
             start.elapsed().as_secs_f64() * 1000.0
         );
-        let next = self.highest_state().await;
-        self.mempool_mut().rebase(next);
+        #[cfg(feature = "metrics")]
+        {
+            crate::metrics::APPLY_BLOCK_SECONDS
+                .observe((apply_time + start.elapsed()).as_secs_f64());
+            crate::metrics::HIGHEST_BLOCK.set(new_state.header().height.0 as i64);
+        }
+        self.mempool_mut().rebase(new_state);
         self.new_block_notify.notify(usize::MAX);
 
         Ok(())
     }
 
+    /// Gathers everything about the state at the given height besides its SMT nodes: the block, its consensus proof, and the stakes.
+    pub async fn get_snapshot_meta(&self, height: BlockHeight) -> Option<SnapshotMeta> {
+        let block = self.get_block(height).await?;
+        let proof = self.get_consensus(height).await?;
+        let stakes = self.get_stake_rows(height).await;
+        Some(SnapshotMeta {
+            block,
+            proof,
+            stakes,
+        })
+    }
+
+    /// Writes a self-contained snapshot of the state at the given height to a file, returning the number of SMT nodes written.
+    pub async fn export_snapshot(&self, height: BlockHeight, path: PathBuf) -> anyhow::Result<u64> {
+        let meta = self
+            .get_snapshot_meta(height)
+            .await
+            .context("no block at that height")?;
+        let forest = self.forest.clone();
+        smol::unblock(move || {
+            let out = std::io::BufWriter::new(std::fs::File::create(&path)?);
+            let mut writer = SnapshotWriter::new(out, &meta)?;
+            let roots = state_roots(&meta.block.header);
+            let count = gc::walk_reachable(forest.storage(), roots, |hash, raw| {
+                writer.write_node(hash, raw)
+            })?;
+            writer.finish()?;
+            Ok(count)
+        })
+        .await
+    }
+
+    /// Seeds an empty database with a snapshot written by [Storage::export_snapshot]. The snapshot must be of the given trusted checkpoint; everything else in it is verified against the checkpoint's header.
+    pub async fn import_snapshot(
+        &self,
+        path: PathBuf,
+        checkpoint: Checkpoint,
+    ) -> anyhow::Result<()> {
+        let staging_path = self.staging_path();
+        let (meta, staging) = {
+            let checkpoint = checkpoint.clone();
+            smol::unblock(move || {
+                let input = std::io::BufReader::new(std::fs::File::open(&path)?);
+                let (meta, mut reader) = SnapshotReader::open(input)?;
+                check_checkpoint(&meta.block.header, &checkpoint)?;
+                let staging = open_staging(&staging_path)?;
+                while let Some((hash, raw)) = reader.next_node()? {
+                    if gc::node_hash(&raw) != Some(hash) {
+                        anyhow::bail!("snapshot has a corrupt SMT node {}", hex::encode(hash));
+                    }
+                    staging.storage().insert(&hash, &raw);
+                }
+                anyhow::Ok((meta, staging))
+            })
+            .await?
+        };
+        self.import_staged(checkpoint, meta, staging, |_| async {
+            anyhow::bail!("snapshot is missing SMT nodes")
+        })
+        .await
+    }
+
+    /// Seeds an empty database with the state at a trusted checkpoint, given everything but its SMT nodes. The nodes are fetched in batches through `fetch`, which must return the raw nodes with the given hashes, in order. Everything is verified against the checkpoint before use.
+    pub async fn import_state<F, Fut>(
+        &self,
+        checkpoint: Checkpoint,
+        meta: SnapshotMeta,
+        fetch: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(Vec<Hashed>) -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<Vec<u8>>>>,
+    {
+        check_checkpoint(&meta.block.header, &checkpoint)?;
+        let staging_path = self.staging_path();
+        let staging = smol::unblock(move || open_staging(&staging_path)).await?;
+        self.import_staged(checkpoint, meta, staging, fetch).await
+    }
+
+    /// Path of the scratch database where the SMT nodes of a state being imported are kept until verified.
+    fn staging_path(&self) -> PathBuf {
+        self.sqlite_path.with_file_name("import-staging.db")
+    }
+
+    /// Finishes importing a state, whose SMT nodes are taken from `staging` where present and otherwise fetched through `fetch`.
+    async fn import_staged<F, Fut>(
+        &self,
+        checkpoint: Checkpoint,
+        meta: SnapshotMeta,
+        staging: novasmt::Database<MeshaCas>,
+        mut fetch: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(Vec<Hashed>) -> Fut,
+        Fut: Future<Output = anyhow::Result<Vec<Vec<u8>>>>,
+    {
+        if self.highest_height().await.0 > 0 {
+            anyhow::bail!("states can only be imported into an empty database");
+        }
+        check_checkpoint(&meta.block.header, &checkpoint)?;
+        let roots = state_roots(&meta.block.header);
+
+        // walk down the trees level by level, fetching whatever isn't staged yet
+        let mut frontier = roots.to_vec();
+        let mut seen: HashSet<Hashed> = HashSet::new();
+        let mut fetched = 0usize;
+        while !frontier.is_empty() {
+            let mut next = vec![];
+            let mut missing = vec![];
+            for node in frontier {
+                if node == [0; 32] || !seen.insert(node) {
+                    continue;
+                }
+                // a staged node that doesn't hash right is fetched again, like one that isn't staged at all
+                match staging.storage().get(&node) {
+                    Some(raw) if gc::node_hash(&raw) == Some(node) => {
+                        next.extend(gc::node_children(&raw))
+                    }
+                    _ => missing.push(node),
+                }
+            }
+            for batch in missing.chunks(IMPORT_FETCH_BATCH) {
+                let raws = fetch(batch.to_vec()).await?;
+                if raws.len() != batch.len() {
+                    anyhow::bail!("asked for {} SMT nodes, got {}", batch.len(), raws.len());
+                }
+                for (node, raw) in batch.iter().zip(raws) {
+                    if raw.is_empty() {
+                        anyhow::bail!("SMT node {} is unavailable", hex::encode(node));
+                    }
+                    // never stage anything that doesn't hash to what we asked for
+                    if gc::node_hash(&raw) != Some(*node) {
+                        anyhow::bail!("got a corrupt SMT node for {}", hex::encode(node));
+                    }
+                    staging.storage().insert(node, &raw);
+                    next.extend(gc::node_children(&raw));
+                }
+                fetched += batch.len();
+                log::debug!("fetched {fetched} SMT nodes, {} seen so far", seen.len());
+            }
+            frontier = next;
+        }
+
+        // every staged node was checked against its hash and every reachable one is there, so novasmt can safely walk them
+        // only what novasmt itself recomputes from the staged leaves makes it into the forest
+        let forest = self.forest.clone();
+        let staging_path = self.staging_path();
+        smol::unblock(move || {
+            let verified = roots.iter().all(|root| {
+                let mut rebuilt = forest.get_tree(Default::default()).unwrap();
+                for (key, value) in staging.get_tree(*root).unwrap().iter() {
+                    rebuilt.insert(key, &value);
+                }
+                rebuilt.root_hash() == *root
+            });
+            // bad staged nodes must not survive into the next attempt
+            drop(staging);
+            std::fs::remove_file(&staging_path)?;
+            if !verified {
+                anyhow::bail!("imported SMTs do not match the header");
+            }
+            anyhow::Ok(())
+        })
+        .await?;
+
+        let blk = meta.block;
+        let stakeset = stakeset_at(
+            meta.stakes
+                .iter()
+                .map(|(txhash, _, doc)| (*txhash, doc.clone())),
+            blk.header.height,
+        );
+        if HashVal(stakeset.pre_tip911().root_hash()) != blk.header.stakes_hash {
+            anyhow::bail!("imported stakes do not match the header");
+        }
+        let transactions_root = self.insert_transactions_smt(&blk).root_hash();
+        if HashVal(transactions_root) != blk.header.transactions_hash {
+            anyhow::bail!("imported block transactions do not match the header");
+        }
+        self.forest.storage().flush();
+
+        {
+            // fetching can take a long time, so blocks are only held off for the final commit
+            let _guard = self.lock.lock().await;
+            if self.highest_height().await.0 > 0 {
+                anyhow::bail!("states can only be imported into an empty database");
+            }
+            let conn = self.recv_pool.recv().await?;
+            let send_pool = self.send_pool.clone();
+            let cproof = meta.proof;
+            let stakes = meta.stakes;
+            let blk = blk.clone();
+            let stake_history = self.stake_history.clone();
+            smol::unblock(move || {
+                let mut conn = scopeguard::guard(conn, |conn| send_pool.try_send(conn).unwrap());
+                let conn = conn.transaction()?;
+                conn.execute(
+                    "insert into history (height, header, block) values ($1, $2, $3)",
+                    params![blk.header.height.0, blk.header.stdcode(), blk.stdcode()],
+                )?;
+                conn.execute(
+                    "insert into consensus_proofs (height, proof) values ($1, $2)",
+                    params![blk.header.height.0, stdcode::serialize(&cproof).unwrap()],
+                )?;
+                conn.execute(
+                    "insert into transaction_smts (height, root) values ($1, $2)",
+                    params![blk.header.height.0, transactions_root.to_vec()],
+                )?;
+                insert_tx_index(&conn, &blk)?;
+                for (txhash, height, doc) in stakes {
+                    conn.execute(
+                        "insert into stakes (txhash, height, stake_doc) values ($1, $2, $3) on conflict do nothing",
+                        params![txhash.to_string(), height.0, doc.stdcode()],
+                    )?;
+                }
+                // like in apply_block, the stakes in memory must be up to date before the block is visible
+                *stake_history.write() = StakeHistory::new(read_stake_rows(&conn, blk.header.height)?);
+                conn.commit()?;
+                anyhow::Ok(())
+            })
+            .await?
+        }
+        self.stakeset_cache.invalidate_all();
+        log::info!(
+            "imported the state of block {} / {}",
+            blk.header.height,
+            blk.header.hash()
+        );
+        let next = self.highest_state().await?;
+        self.mempool_mut().rebase(next);
+        self.new_block_notify.notify(usize::MAX);
+        Ok(())
+    }
+
     /// Gets the forest.
     pub fn forest(&self) -> &novasmt::Database<MeshaCas> {
         &self.forest
     }
 }
+
+/// The roots of the state SMTs referenced by a header.
+fn state_roots(header: &Header) -> [Hashed; 3] {
+    [
+        header.coins_hash.0,
+        header.history_hash.0,
+        header.pools_hash.0,
+    ]
+}
+
+/// Checks that a header is the one of a trusted checkpoint.
+fn check_checkpoint(header: &Header, checkpoint: &Checkpoint) -> anyhow::Result<()> {
+    if header.height != checkpoint.height || header.hash() != checkpoint.header_hash {
+        anyhow::bail!(
+            "got block {}/{}, not the trusted checkpoint {}/{}",
+            header.height,
+            header.hash(),
+            checkpoint.height,
+            checkpoint.header_hash
+        );
+    }
+    Ok(())
+}
+
+/// Opens the scratch database for staging SMT nodes. Whatever an interrupted import left there is kept, so that the import can resume; staged nodes are checked against their hashes before use anyway.
+fn open_staging(path: &Path) -> anyhow::Result<novasmt::Database<MeshaCas>> {
+    Ok(novasmt::Database::new(MeshaCas::new(
+        meshanina::Mapping::open(path)?,
+    )))
+}
+
+/// Reads every row of the stakes table up to a given height, as (staking txhash, height, stake doc).
+fn read_stake_rows(
+    conn: &rusqlite::Connection,
+    height: BlockHeight,
+) -> anyhow::Result<Vec<(TxHash, BlockHeight, StakeDoc)>> {
+    let mut stmt = conn.prepare(
+        "select txhash, height, stake_doc from stakes where height <= $1 order by height",
+    )?;
+    let mut stakes = vec![];
+    for row in stmt.query_map(params![height.0], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })? {
+        let row: (String, u64, Vec<u8>) = row?;
+        let t: TxHash = row.0.parse()?;
+        let sd: StakeDoc = stdcode::deserialize(&row.2)?;
+        stakes.push((t, BlockHeight(row.1), sd));
+    }
+    Ok(stakes)
+}
+
+/// Builds the stakeset at a given height out of the rows of the stakes table up to it, which include the genesis stakes.
+fn stakeset_at(
+    stakes: impl IntoIterator<Item = (TxHash, StakeDoc)>,
+    height: BlockHeight,
+) -> StakeSet {
+    let mut stakeset = StakeSet::new(vec![].into_iter());
+    for (txhash, stake) in stakes {
+        stakeset.add_stake(txhash, stake);
+    }
+    stakeset.unlock_old(height.epoch());
+    stakeset
+}
+
+/// Computes the paths of the SQLite and meshanina databases for the given genesis, creating their folder if needed.
+fn db_paths(mut db_folder: PathBuf, genesis: &GenesisConfig) -> anyhow::Result<(PathBuf, PathBuf)> {
+    let genesis_id = tmelcrypt::hash_single(stdcode::serialize(genesis).unwrap());
+    db_folder.push(format!("{}/", hex::encode(genesis_id.0)));
+    std::fs::create_dir_all(&db_folder).context("cannot make folder")?;
+    let sqlite_path = db_folder.clone().tap_mut(|path| path.push("storage.db"));
+    let mesha_path = db_folder.tap_mut(|path| path.push("merkle.db"));
+    Ok((sqlite_path, mesha_path))
+}
+
+/// Rewrites the meshanina database at the given path, keeping only the SMT nodes reachable from the states and transaction SMTs still in the history.
+fn compact_forest(
+    conn: &rusqlite::Connection,
+    mesha_path: &Path,
+) -> anyhow::Result<CompactionStats> {
+    let mut roots = vec![];
+    let mut stmt = conn.prepare("select header from history")?;
+    for header in stmt.query_map(params![], |r| r.get::<_, Vec<u8>>(0))? {
+        let header: Header = stdcode::deserialize(&header?)?;
+        roots.extend([
+            header.coins_hash.0,
+            header.history_hash.0,
+            header.pools_hash.0,
+        ]);
+    }
+    let mut stmt = conn.prepare("select root from transaction_smts")?;
+    for root in stmt.query_map(params![], |r| r.get::<_, Vec<u8>>(0))? {
+        roots.push(root?.as_slice().try_into()?);
+    }
+
+    let compacted_path = mesha_path.with_extension("db.compacting");
+    if compacted_path.exists() {
+        // left over from an interrupted collection
+        std::fs::remove_file(&compacted_path)?;
+    }
+    let nodes_kept = {
+        let old = MeshaCas::new(meshanina::Mapping::open(mesha_path)?);
+        let new = MeshaCas::new(meshanina::Mapping::open(&compacted_path)?);
+        let nodes_kept = gc::copy_reachable(&old, &new, roots)?;
+        new.flush();
+        nodes_kept
+    };
+    let stats = CompactionStats {
+        nodes_kept,
+        bytes_before: std::fs::metadata(mesha_path)?.len(),
+        bytes_after: std::fs::metadata(&compacted_path)?.len(),
+    };
+    std::fs::rename(&compacted_path, mesha_path)?;
+    // whatever pruning orphaned is gone now
+    conn.execute(
+        "delete from misc where key = $1",
+        params![FOREST_GC_PENDING_KEY],
+    )?;
+    Ok(stats)
+}
+
+/// Adds every transaction in the block to the transaction index. Indices are positions within the block's transactions sorted by hash.
+fn insert_tx_index(conn: &rusqlite::Connection, blk: &Block) -> rusqlite::Result<()> {
+    for (idx, txhash) in blk.abbreviate().txhashes.iter().enumerate() {
+        conn.execute(
+            "insert into txindex (txhash, height, idx) values ($1, $2, $3) on conflict do nothing",
+            params![txhash.to_string(), blk.header.height.0, idx as u32],
+        )?;
+    }
+    Ok(())
+}
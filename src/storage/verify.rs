@@ -0,0 +1,311 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use melstf::SealedState;
+use melstructs::{Block, BlockHeight, ConsensusProof, StakeDoc};
+use tmelcrypt::Ed25519PK;
+
+use super::MeshaCas;
+
+/// A block whose consensus proof signatures have already been checked, ready for [super::Storage::apply_verified_block]. Which of the signers are actually stakers, and how much they stake, can only be checked against the state the block applies to.
+pub struct VerifiedBlock {
+    pub(super) block: Block,
+    pub(super) proof: ConsensusProof,
+    /// Public keys with a valid signature on the block header.
+    pub(super) signers: HashSet<Ed25519PK>,
+}
+
+impl VerifiedBlock {
+    /// Checks the signatures in the consensus proof of a block. Invalid signatures are simply left out, just like signatures from non-stakers are ignored later.
+    pub fn new(block: Block, proof: ConsensusProof) -> Self {
+        let header_hash = block.header.hash();
+        let signers = proof
+            .iter()
+            .filter(|(pubkey, sig)| pubkey.verify(&header_hash, sig))
+            .map(|(pubkey, _)| *pubkey)
+            .collect();
+        Self {
+            block,
+            proof,
+            signers,
+        }
+    }
+}
+
+/// Verifies a batch of consecutive blocks, as downloaded during sync, on the blocking thread pool so that the signatures of different blocks are checked in parallel. Also checks that the headers form a chain, so that a batch that doesn't can be rejected before anything is applied.
+pub async fn verify_blocks(
+    blocks: Vec<(Block, ConsensusProof)>,
+) -> anyhow::Result<Vec<VerifiedBlock>> {
+    for pair in blocks.windows(2) {
+        let (prev, next) = (&pair[0].0.header, &pair[1].0.header);
+        if next.height != prev.height + 1.into() {
+            anyhow::bail!("block {} follows block {}", next.height, prev.height);
+        }
+        if next.previous != prev.hash() {
+            anyhow::bail!(
+                "block {} does not point to the previous block {}",
+                next.height,
+                prev.height
+            );
+        }
+    }
+    Ok(futures_util::future::join_all(
+        blocks
+            .into_iter()
+            .map(|(block, proof)| smol::unblock(move || VerifiedBlock::new(block, proof))),
+    )
+    .await)
+}
+
+/// The voting weight of every staker in the consensus on the block right after the given state. A public key staking through several stake docs votes with their combined weight. Both the staker, when gathering signatures, and [super::Storage::apply_block], when checking them, weigh votes this way.
+pub fn vote_weights(state: &SealedState<MeshaCas>) -> anyhow::Result<BTreeMap<Ed25519PK, u128>> {
+    let epoch = (state.header().height + BlockHeight(1)).epoch();
+    let stake_docs = state
+        .raw_stakes()
+        .pre_tip911()
+        .iter()
+        .map(|(_, stake_doc)| stdcode::deserialize(&stake_doc))
+        .collect::<Result<Vec<StakeDoc>, _>>()?;
+    Ok(weigh_stakes(epoch, &stake_docs))
+}
+
+/// Sums up the stake docs active in the given epoch by public key.
+fn weigh_stakes<'a>(
+    epoch: u64,
+    stake_docs: impl IntoIterator<Item = &'a StakeDoc>,
+) -> BTreeMap<Ed25519PK, u128> {
+    let mut weights = BTreeMap::new();
+    for stake_doc in stake_docs {
+        if epoch >= stake_doc.e_start && epoch < stake_doc.e_post_end {
+            *weights.entry(stake_doc.pubkey).or_default() += stake_doc.syms_staked.0;
+        }
+    }
+    weights
+}
+
+/// How much of the voting weight is behind a set of signatures.
+#[derive(Clone, Copy, Debug)]
+pub struct VoteTally {
+    pub present: u128,
+    pub total: u128,
+}
+
+impl VoteTally {
+    /// Tallies the votes of the given signers, weighted by [vote_weights]. Every signer counts once, however many times it's listed, and signers without stake count for nothing.
+    pub fn new<'a>(
+        weights: &BTreeMap<Ed25519PK, u128>,
+        signers: impl IntoIterator<Item = &'a Ed25519PK>,
+    ) -> Self {
+        let signers: BTreeSet<&Ed25519PK> = signers.into_iter().collect();
+        Self {
+            present: signers.into_iter().filter_map(|pk| weights.get(pk)).sum(),
+            total: weights.values().sum(),
+        }
+    }
+
+    /// Whether strictly more than 2/3 of the voting weight is present.
+    pub fn has_quorum(&self) -> bool {
+        // floor(2 * total / 3), without overflowing
+        self.present > self.total / 3 * 2 + self.total % 3 * 2 / 3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use bytes::Bytes;
+    use melstructs::{Block, CoinValue, Header, NetID, StakeDoc};
+    use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
+
+    use super::{weigh_stakes, VerifiedBlock, VoteTally};
+
+    const CASES: u64 = 200;
+
+    fn random_pubkey(rng: &fastrand::Rng) -> Ed25519PK {
+        let mut pubkey = [0u8; 32];
+        rng.fill(&mut pubkey);
+        Ed25519PK(pubkey)
+    }
+
+    fn stake_doc(pubkey: Ed25519PK, e_start: u64, e_post_end: u64, syms: u128) -> StakeDoc {
+        StakeDoc {
+            pubkey,
+            e_start,
+            e_post_end,
+            syms_staked: CoinValue(syms),
+        }
+    }
+
+    fn block_at(height: u64) -> Block {
+        Block {
+            header: Header {
+                network: NetID::Testnet,
+                previous: HashVal::default(),
+                height: height.into(),
+                history_hash: HashVal::default(),
+                coins_hash: HashVal::default(),
+                transactions_hash: HashVal::default(),
+                fee_pool: CoinValue(0),
+                fee_multiplier: 0,
+                dosc_speed: 0,
+                pools_hash: HashVal::default(),
+                stakes_hash: HashVal::default(),
+            },
+            transactions: HashSet::new(),
+            proposer_action: None,
+        }
+    }
+
+    #[test]
+    fn tally_matches_brute_force() {
+        let rng = fastrand::Rng::with_seed(0x5eed);
+        for _ in 0..CASES {
+            let stakers: Vec<(Ed25519PK, u128)> = (0..rng.usize(1..20))
+                .map(|_| (random_pubkey(&rng), rng.u128(0..1 << 64)))
+                .collect();
+            let weights = stakers.iter().copied().collect();
+            // some stakers sign, possibly more than once, along with some non-stakers
+            let mut signers = vec![];
+            let mut present = 0;
+            for (pubkey, weight) in stakers.iter() {
+                if rng.bool() {
+                    present += weight;
+                    for _ in 0..rng.usize(1..3) {
+                        signers.push(*pubkey);
+                    }
+                }
+            }
+            for _ in 0..rng.usize(0..5) {
+                signers.push(random_pubkey(&rng));
+            }
+            rng.shuffle(&mut signers);
+
+            let tally = VoteTally::new(&weights, &signers);
+            let total: u128 = stakers.iter().map(|(_, weight)| weight).sum();
+            assert_eq!(tally.present, present);
+            assert_eq!(tally.total, total);
+            assert_eq!(tally.has_quorum(), 3 * present > 2 * total);
+        }
+    }
+
+    #[test]
+    fn quorum_needs_strictly_more_than_two_thirds() {
+        let rng = fastrand::Rng::with_seed(0xb0a7);
+        let mut totals: Vec<u128> = (0..CASES).map(|_| rng.u128(1..1 << 100)).collect();
+        totals.extend([1, 2, 3, u128::MAX - 2, u128::MAX - 1, u128::MAX]);
+        for total in totals {
+            // the least weight strictly above 2/3 of the total
+            let threshold = total / 3 * 2 + (total % 3 * 2 + 3) / 3;
+            let below = VoteTally {
+                present: threshold - 1,
+                total,
+            };
+            let at = VoteTally {
+                present: threshold,
+                total,
+            };
+            assert!(!below.has_quorum(), "{below:?}");
+            assert!(at.has_quorum(), "{at:?}");
+        }
+        // with a total of 3k, 2k is exactly 2/3 and not enough
+        let exact = VoteTally {
+            present: 2000,
+            total: 3000,
+        };
+        assert!(!exact.has_quorum());
+        assert!(VoteTally {
+            present: 2001,
+            ..exact
+        }
+        .has_quorum());
+    }
+
+    #[test]
+    fn weights_combine_stake_docs_of_the_same_staker() {
+        let rng = fastrand::Rng::with_seed(0xd0c5);
+        for _ in 0..CASES {
+            let pubkeys: Vec<Ed25519PK> =
+                (0..rng.usize(1..6)).map(|_| random_pubkey(&rng)).collect();
+            let epoch = rng.u64(0..10);
+            let stake_docs: Vec<StakeDoc> = (0..rng.usize(1..30))
+                .map(|_| {
+                    let e_start = rng.u64(0..10);
+                    stake_doc(
+                        pubkeys[rng.usize(..pubkeys.len())],
+                        e_start,
+                        e_start + rng.u64(0..5),
+                        rng.u128(1..1 << 64),
+                    )
+                })
+                .collect();
+
+            let weights = weigh_stakes(epoch, &stake_docs);
+            for pubkey in pubkeys.iter() {
+                let expected: u128 = stake_docs
+                    .iter()
+                    .filter(|doc| {
+                        doc.pubkey == *pubkey && doc.e_start <= epoch && epoch < doc.e_post_end
+                    })
+                    .map(|doc| doc.syms_staked.0)
+                    .sum();
+                assert_eq!(weights.get(pubkey).copied().unwrap_or_default(), expected);
+            }
+            assert!(weights.keys().all(|pubkey| pubkeys.contains(pubkey)));
+        }
+    }
+
+    #[test]
+    fn signers_without_stake_count_for_nothing() {
+        let rng = fastrand::Rng::with_seed(0x0);
+        let staker = random_pubkey(&rng);
+        let expired = random_pubkey(&rng);
+        let weights = weigh_stakes(
+            5,
+            &[
+                stake_doc(staker, 0, 10, 100),
+                stake_doc(expired, 0, 5, 1000),
+                stake_doc(expired, 6, 10, 1000),
+            ],
+        );
+        assert!(!weights.contains_key(&expired));
+        let outsiders: Vec<Ed25519PK> = (0..100).map(|_| random_pubkey(&rng)).collect();
+        let tally = VoteTally::new(&weights, outsiders.iter().chain([&expired]));
+        assert_eq!(tally.present, 0);
+        assert_eq!(tally.total, 100);
+        assert!(!tally.has_quorum());
+        assert!(VoteTally::new(&weights, outsiders.iter().chain([&staker])).has_quorum());
+    }
+
+    #[test]
+    fn invalid_signatures_are_left_out() {
+        let rng = fastrand::Rng::with_seed(0x519);
+        for _ in 0..CASES / 10 {
+            let block = block_at(rng.u64(..));
+            let header_hash = block.header.hash();
+            let other_hash = block_at(block.header.height.0 + 1).header.hash();
+            let mut proof = super::ConsensusProof::new();
+            let mut valid = HashSet::new();
+            for _ in 0..rng.usize(1..8) {
+                let sk = Ed25519SK::generate();
+                let pubkey = sk.to_public();
+                let sig = match rng.u8(0..3) {
+                    0 => {
+                        valid.insert(pubkey);
+                        sk.sign(&header_hash)
+                    }
+                    // signs some other header
+                    1 => sk.sign(&other_hash),
+                    // garbage
+                    _ => {
+                        let mut sig = sk.sign(&header_hash);
+                        let i = rng.usize(..sig.len());
+                        sig[i] ^= 1 << rng.u8(0..8);
+                        sig
+                    }
+                };
+                proof.insert(pubkey, Bytes::from(sig));
+            }
+            assert_eq!(VerifiedBlock::new(block, proof).signers, valid);
+        }
+    }
+}